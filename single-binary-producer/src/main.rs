@@ -1,9 +1,9 @@
 use axum::{
-    extract::Path as AxumPath,
     http::{
-        header::{HeaderName, HeaderValue},
-        StatusCode,
+        header::{self, HeaderName, HeaderValue},
+        HeaderMap, Request, StatusCode, Uri,
     },
+    middleware::{self, Next},
     response::{IntoResponse, IntoResponseParts, Response, ResponseParts},
     routing::get,
     Router,
@@ -12,16 +12,184 @@ use clap::{
     error::{Error as ClapError, ErrorKind},
     Command as ClapCommand,
 };
-use include_directory::{include_directory, Dir};
+use hyper::server::{accept::Accept, conn::AddrIncoming, Builder};
+use include_directory::{include_directory, Dir, File};
 use once_cell::sync::Lazy;
-use std::{fmt::Display, net::SocketAddr, process, str::FromStr, time::Duration};
-use tokio::{net as tokio_net, time as tokio_time};
+use percent_encoding::percent_decode_str;
+use socket2::{Domain, Socket, Type};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    iter,
+    net::{SocketAddr, TcpListener},
+    path::Path,
+    process,
+    str::FromStr,
+    time::Duration,
+};
 
-const ADDR: Lazy<SocketAddr> = Lazy::new(|| SocketAddr::from_str("127.0.0.1:8080").unwrap());
+const ADDRS: &[&str] = &["127.0.0.1:8080"];
 const CACHE: i64 = 3600;
-const CORS: Lazy<String> = Lazy::new(|| String::from("*"));
+const CORS: &str = "*";
+const SPA: bool = false;
 const INDEX_FILES: [&str; 4] = ["index.html", "index.htm", "index.xhtml", "index.shtml"];
 const PROJECT_DIR: Dir = include_directory!("./_public_dir_");
+static ROOT_MOUNT: Mount = Mount {
+    prefix: "",
+    dir: PROJECT_DIR,
+    index: None,
+    spa: SPA,
+    cache: CACHE,
+    cors: CORS,
+    content_types: ROOT_CONTENT_TYPES,
+};
+// Filled with the Content-Type of every embedded file of the served directory, sorted by path
+static ROOT_CONTENT_TYPES: &[(&str, &str)] = &[];
+// Filled with the other mounted directories when the binary gets produced
+static MOUNTS: &[Mount] = &[];
+// Redirect rules in the `_redirects` syntax, collected from the served directory and the config file
+const REDIRECTS: &str = "_redirects_";
+const REDIRECTS_FILE: &str = "_redirects";
+const REDIRECT_STATUSES: [u16; 5] = [301, 302, 303, 307, 308];
+const REWRITE_STATUSES: [u16; 2] = [200, 404];
+// Rules of the default host followed by the rules of every virtual host
+static RULES: Lazy<Vec<Vec<Rule>>> = Lazy::new(|| {
+    iter::once(REDIRECTS)
+        .chain(HOSTS.iter().map(|host| host.redirects))
+        .map(parse_rules)
+        .collect()
+});
+const CLEAN_URLS: bool = false;
+// Empty unless the binary is served under a URL prefix
+const BASE_PATH: &str = "";
+const TRAILING_SLASH: Option<TrailingSlash> = None;
+// Set when the binary listens on a Unix domain socket instead of the address
+#[cfg(unix)]
+const UNIX_SOCKET: Option<&str> = None;
+#[cfg(unix)]
+const UNIX_MODE: Option<u32> = None;
+const HEALTH_CHECKS: bool = false;
+const HEALTH_PATH: &str = "/healthz";
+const READY_PATH: &str = "/readyz";
+// The version of static-file-http-server that produced the binary and when, in seconds since the
+// Unix epoch
+const BUILD_VERSION: &str = "0.0.0";
+const BUILT_AT: u64 = 0;
+// Timeouts in seconds, 0 disables them
+const HEADER_READ_TIMEOUT: u64 = 10;
+const IDLE_TIMEOUT: u64 = 60;
+const WRITE_TIMEOUT: u64 = 30;
+const MAX_HEADER_SIZE: usize = 32768;
+// With the charset the binary was produced with
+const ERROR_PAGE_CONTENT_TYPE: &str = "text/html; charset=utf-8";
+// Connections beyond the limit are closed right away, 0 disables it
+const MAX_CONNECTIONS: usize = 10000;
+// Headers set on the responses to matching paths, later rules win. Empty values remove the header.
+static HEADER_RULES: &[(&str, &[(&str, &str)])] = &[];
+
+// The variant is picked when the binary gets produced
+#[allow(dead_code)]
+enum TrailingSlash {
+    Always,
+    Never,
+}
+
+// An embedded directory served under a URL prefix
+struct Mount {
+    // Empty for the root mount, otherwise starts with '/' and has no trailing slash
+    prefix: &'static str,
+    dir: Dir<'static>,
+    index: Option<&'static str>,
+    spa: bool,
+    cache: i64,
+    cors: &'static str,
+    // Computed with the MIME types and charset the binary was produced with, sorted by path
+    content_types: &'static [(&'static str, &'static str)],
+}
+
+// An embedded site chosen by the `Host` header
+struct Host {
+    // Exact names like `example.com` or wildcards like `*.preview.local`
+    names: &'static [&'static str],
+    root: Mount,
+    redirects: &'static str,
+}
+// Filled with the virtual hosts when the binary gets produced. Unmatched requests are served by the
+// default host made of the mounts above.
+static HOSTS: &[Host] = &[];
+
+// Returns 0 for the default host and `i + 1` for `HOSTS[i]`. Exact names win over wildcards, and
+// longer wildcards win over shorter ones.
+fn find_host(headers: &HeaderMap, uri: &Uri) -> usize {
+    let host = match headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+    {
+        Some(host) => host,
+        None => uri.host().unwrap_or_default(),
+    };
+    let name = match host.strip_prefix('[') {
+        Some(ipv6) => ipv6.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    };
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+
+    if let Some(i) = HOSTS
+        .iter()
+        .position(|host| host.names.contains(&name.as_str()))
+    {
+        return i + 1;
+    }
+
+    HOSTS
+        .iter()
+        .enumerate()
+        .flat_map(|(i, host)| host.names.iter().map(move |host_name| (i, host_name)))
+        .filter_map(|(i, host_name)| {
+            let domain = host_name.strip_prefix("*.")?;
+            let subdomain = name.strip_suffix(domain)?;
+            (subdomain.len() > 1 && subdomain.ends_with('.')).then_some((i + 1, domain.len()))
+        })
+        .max_by_key(|(_, len)| *len)
+        .map(|(i, _)| i)
+        .unwrap_or(0)
+}
+
+fn root_mount(host: usize) -> &'static Mount {
+    match host {
+        0 => &ROOT_MOUNT,
+        _ => &HOSTS[host - 1].root,
+    }
+}
+
+// Finds the mount with the longest prefix matching the path and returns the path relative to it
+fn find_mount(host: usize, path: &str) -> (&'static Mount, String) {
+    let path = path.trim_start_matches('/');
+    if host != 0 {
+        return (root_mount(host), String::from(path));
+    }
+
+    MOUNTS
+        .iter()
+        .filter_map(
+            |mount| match path.strip_prefix(mount.prefix.trim_start_matches('/')) {
+                Some("") => Some((mount, String::new())),
+                Some(rest) => rest
+                    .strip_prefix('/')
+                    .map(|rest| (mount, String::from(rest))),
+                None => None,
+            },
+        )
+        .max_by_key(|(mount, _)| mount.prefix.len())
+        .unwrap_or_else(|| (&ROOT_MOUNT, String::from(path)))
+}
+
+fn index_file(mount: &Mount) -> String {
+    match mount.index {
+        Some(index) => String::from(index),
+        None => root_serve(&mount.dir),
+    }
+}
 
 pub fn unrecoverable_clap_error(message: impl Display) {
     let cmd = ClapCommand::new("static-file-http-server");
@@ -89,42 +257,532 @@ fn set_cache_control(cache_control: &i64) -> Option<SetHeader> {
 }
 
 // 1.HTTP status code 2.Content-Type header 3.CORS header 4.Cache-Control header 5.Body
-type HandlerReturnType = (StatusCode, SetHeader, SetHeader, Option<SetHeader>, String);
+type HandlerReturnType = (StatusCode, SetHeader, SetHeader, Option<SetHeader>, Vec<u8>);
 
-async fn handler(path: String) -> HandlerReturnType {
-    match PROJECT_DIR.get_file(&path) {
+fn content_type(mount: &Mount, path: &str, file: &File) -> String {
+    match mount
+        .content_types
+        .binary_search_by(|(file, _)| (*file).cmp(path))
+    {
+        Ok(i) => String::from(mount.content_types[i].1),
+        Err(_) => file.mimetype_as_string(),
+    }
+}
+
+async fn handler(path: String, mount: &Mount) -> HandlerReturnType {
+    match mount.dir.get_file(&path) {
         Some(file) => (
             StatusCode::OK,
-            set_content_type(&file.mimetype_as_string()),
-            set_cors_access(&CORS.as_str()),
-            set_cache_control(&CACHE),
-            String::from(file.contents_utf8().unwrap_or_default()),
+            set_content_type(&content_type(mount, &path, file)),
+            set_cors_access(&mount.cors),
+            set_cache_control(&mount.cache),
+            file.contents().to_vec(),
         ),
-        None => {
-            let body = match PROJECT_DIR.get_file("404.html") {
-                Some(file) => {
-                    if let Some(contents) = file.contents_utf8() {
-                        contents
-                    } else {
-                        "File not found!"
-                    }
-                }
-                None => "File not found!",
+        None => error_page(StatusCode::NOT_FOUND, &path, mount),
+    }
+}
+
+fn default_error_page(status: StatusCode) -> String {
+    let reason = status.canonical_reason().unwrap_or("Error");
+
+    format!(
+        "<!DOCTYPE html>
+<html lang='en'>
+<head>
+<meta charset='utf-8'>
+<meta name='viewport' content='width=device-width, initial-scale=1'>
+<title>{code} {reason}</title>
+<style>
+body {{ margin: 0; min-height: 100vh; display: flex; align-items: center; justify-content: center; font-family: system-ui, sans-serif; background: #f6f7f9; color: #1f2328; }}
+main {{ text-align: center; }}
+h1 {{ margin: 0; font-size: 5rem; font-weight: 700; color: #57606a; }}
+p {{ margin: 0.5rem 0 0; font-size: 1.25rem; }}
+</style>
+</head>
+<body>
+<main>
+<h1>{code}</h1>
+<p>{reason}</p>
+</main>
+</body>
+</html>
+",
+        code = status.as_u16(),
+        reason = reason,
+    )
+}
+
+// Looks for `<status>.html` in the directory of the requested path and then its parents up to the
+// mounted directory, falling back to a built-in page
+fn error_page(status: StatusCode, path: &str, mount: &Mount) -> HandlerReturnType {
+    let file_name = format!("{}.html", status.as_u16());
+    let dir = if path.is_empty() || path.ends_with('/') {
+        Path::new(path.trim_end_matches('/'))
+    } else {
+        Path::new(path).parent().unwrap_or(Path::new(""))
+    };
+
+    let body = dir
+        .ancestors()
+        .find_map(|dir| mount.dir.get_file(dir.join(&file_name)))
+        .map(|file| file.contents().to_vec())
+        .unwrap_or_else(|| default_error_page(status).into_bytes());
+
+    // Only missing files are cached, other errors are likely temporary
+    let cache = if status == StatusCode::NOT_FOUND {
+        mount.cache
+    } else {
+        -1
+    };
+
+    (
+        status,
+        set_content_type(&ERROR_PAGE_CONTENT_TYPE),
+        set_cors_access(&mount.cors),
+        set_cache_control(&cache),
+        body,
+    )
+}
+
+async fn method_not_allowed(headers: HeaderMap, uri: Uri) -> HandlerReturnType {
+    let path = strip_base_path(uri.path()).unwrap_or_default();
+    let (mount, path) = find_mount(find_host(&headers, &uri), path);
+
+    error_page(StatusCode::METHOD_NOT_ALLOWED, &path, mount)
+}
+
+// Strips the base path from the request path. Paths outside of the base path are rejected.
+fn strip_base_path(path: &str) -> Option<&str> {
+    match path.strip_prefix(BASE_PATH) {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => Some(rest),
+        _ => None,
+    }
+}
+
+async fn set_headers<B>(req: Request<B>, next: Next<B>) -> Response {
+    let path = strip_base_path(req.uri().path())
+        .and_then(relative_path)
+        .map(|path| format!("/{}", path))
+        .unwrap_or_else(|| String::from(req.uri().path()));
+
+    let mut res = next.run(req).await;
+    for (pattern, values) in HEADER_RULES {
+        if match_path(pattern, &path).is_none() {
+            continue;
+        }
+
+        for (name, value) in values.iter() {
+            let name = match HeaderName::from_str(name) {
+                Ok(name) => name,
+                Err(_) => continue,
             };
+            match HeaderValue::from_str(value) {
+                Ok(value) if !value.is_empty() => res.headers_mut().insert(name, value),
+                _ => res.headers_mut().remove(name),
+            };
+        }
+    }
+
+    res
+}
 
-            (
-                StatusCode::NOT_FOUND,
-                set_content_type(&"text/html"),
-                set_cors_access(&CORS.as_str()),
-                set_cache_control(&CACHE),
-                String::from(body),
+fn with_base_path(location: String) -> String {
+    if location.starts_with('/') && !location.starts_with("//") {
+        format!("{}{}", BASE_PATH, location)
+    } else {
+        location
+    }
+}
+
+// A single rule of a Netlify compatible `_redirects` file
+struct Rule {
+    from: String,
+    query: Vec<(String, String)>,
+    to: String,
+    status: u16,
+    force: bool,
+}
+
+enum Action {
+    Redirect {
+        status: StatusCode,
+        location: String,
+    },
+    Rewrite {
+        status: StatusCode,
+        path: String,
+    },
+}
+
+// Matches a path against a pattern made of `:placeholder` segments and an optional trailing `/*`.
+// Returns the values of the placeholders and the splat.
+fn match_path(pattern: &str, path: &str) -> Option<HashMap<String, String>> {
+    let mut params = HashMap::new();
+
+    let pattern = pattern.trim_end_matches('/');
+    let path = path.trim_end_matches('/');
+    let (pattern, splat) = match pattern.strip_suffix("/*") {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+    let mut pattern_segments = pattern.split('/').skip(1);
+    let mut path_segments = path.split('/').skip(1);
+    loop {
+        match (pattern_segments.next(), path_segments.next()) {
+            (Some(pattern), Some(segment)) => match pattern.strip_prefix(':') {
+                Some(name) if !segment.is_empty() => {
+                    params.insert(String::from(name), String::from(segment));
+                }
+                Some(_) => return None,
+                None if pattern == segment => {}
+                None => return None,
+            },
+            (None, Some(segment)) if splat => {
+                let splat: Vec<&str> = iter::once(segment).chain(path_segments).collect();
+                params.insert(String::from("splat"), splat.join("/"));
+                break;
+            }
+            (None, None) => {
+                if splat {
+                    params.insert(String::from("splat"), String::new());
+                }
+                break;
+            }
+            _ => return None,
+        }
+    }
+
+    Some(params)
+}
+
+fn is_condition(token: &str) -> bool {
+    token.contains('=') && !token.starts_with('/') && !token.starts_with("http")
+}
+
+impl Rule {
+    // The rules are validated before getting embedded so invalid lines are skipped
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        let mut tokens = line.split_whitespace().peekable();
+        let from = String::from(tokens.next()?);
+        let mut query = vec![];
+        while let Some(token) = tokens.next_if(|token| is_condition(token)) {
+            let (name, value) = token.split_once('=').unwrap_or_default();
+            query.push((String::from(name), String::from(value)));
+        }
+        let to = String::from(tokens.next()?);
+        let (status, force) = match tokens.next() {
+            Some(status) => match status.strip_suffix('!') {
+                Some(status) => (status.parse().ok()?, true),
+                None => (status.parse().ok()?, false),
+            },
+            None => (301, false),
+        };
+        if !REDIRECT_STATUSES.contains(&status) && !REWRITE_STATUSES.contains(&status) {
+            return None;
+        }
+
+        Some(Self {
+            from,
+            query,
+            to,
+            status,
+            force,
+        })
+    }
+
+    fn matches(
+        &self,
+        path: &str,
+        query: &HashMap<String, String>,
+    ) -> Option<HashMap<String, String>> {
+        let mut params = match_path(&self.from, path)?;
+        for (name, expected) in &self.query {
+            let value = query.get(name)?;
+            match expected.strip_prefix(':') {
+                Some(placeholder) => {
+                    params.insert(String::from(placeholder), value.clone());
+                }
+                None if expected == value => {}
+                None => return None,
+            }
+        }
+
+        Some(params)
+    }
+
+    fn target(&self, params: &HashMap<String, String>) -> String {
+        let mut out = String::new();
+        let mut rest = self.to.as_str();
+        while let Some(index) = rest.find(':') {
+            out.push_str(&rest[..index]);
+            rest = &rest[index + 1..];
+
+            let name_len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            let name = &rest[..name_len];
+            match params.get(name) {
+                Some(value) if name.starts_with(|c: char| c.is_ascii_alphabetic()) => {
+                    out.push_str(value);
+                    rest = &rest[name_len..];
+                }
+                _ => out.push(':'),
+            }
+        }
+        out.push_str(rest);
+
+        out
+    }
+}
+
+fn parse_rules(contents: &str) -> Vec<Rule> {
+    contents.lines().filter_map(Rule::parse).collect()
+}
+
+fn decode(value: &str) -> String {
+    percent_decode_str(&value.replace('+', " "))
+        .decode_utf8_lossy()
+        .to_string()
+}
+
+fn parse_query(query: Option<&str>) -> HashMap<String, String> {
+    query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((name, value)) => (decode(name), decode(value)),
+            None => (decode(pair), String::new()),
+        })
+        .collect()
+}
+
+// Finds the first matching rule. Rules that aren't forced are shadowed by existing files.
+fn resolve_rules(
+    host: usize,
+    path: &str,
+    query: Option<&str>,
+    file_exists: impl Fn(&str) -> bool,
+) -> Option<Action> {
+    let query_params = parse_query(query);
+    let (rule, params) = RULES[host].iter().find_map(|rule| {
+        rule.matches(path, &query_params)
+            .map(|params| (rule, params))
+    })?;
+    if !rule.force && file_exists(path) {
+        return None;
+    }
+
+    let status = StatusCode::from_u16(rule.status).ok()?;
+    let mut target = rule.target(&params);
+    if REWRITE_STATUSES.contains(&rule.status) {
+        if let Some((path, _)) = target.split_once('?') {
+            target = String::from(path);
+        }
+
+        return Some(Action::Rewrite {
+            status,
+            path: target,
+        });
+    }
+
+    // Query strings are passed through unless the rule matched on them
+    if let Some(query) = query {
+        if rule.query.is_empty() && !target.contains('?') && !query.is_empty() {
+            target = format!("{}?{}", target, query);
+        }
+    }
+
+    Some(Action::Redirect {
+        status,
+        location: target,
+    })
+}
+
+// Decodes the request path and strips its leading slash. Paths escaping the served directory are rejected.
+fn relative_path(path: &str) -> Option<String> {
+    let path = percent_decode_str(path).decode_utf8().ok()?;
+    let path = path.trim_start_matches('/');
+    if path.split(['/', '\\']).any(|segment| segment == "..") {
+        return None;
+    }
+
+    Some(String::from(path))
+}
+
+async fn serve(headers: HeaderMap, uri: Uri) -> Response {
+    let host = find_host(&headers, &uri);
+    let request_path = match strip_base_path(uri.path()) {
+        Some(request_path) => request_path,
+        None if uri.path() == "/" => {
+            return redirect(
+                StatusCode::MOVED_PERMANENTLY,
+                String::from("/"),
+                root_mount(host),
             )
         }
+        None => return error_page(StatusCode::NOT_FOUND, "", root_mount(host)).into_response(),
+    };
+    let path = match relative_path(request_path) {
+        Some(path) if path != REDIRECTS_FILE => path,
+        _ => return error_page(StatusCode::NOT_FOUND, "", root_mount(host)).into_response(),
+    };
+
+    let file_exists = |path: &str| {
+        let (mount, path) = find_mount(host, path);
+        let resolved = resolve_path(&path, mount);
+        mount.dir.get_file(resolved.file).is_some()
+    };
+    let (status, path, rewritten) =
+        match resolve_rules(host, &format!("/{}", path), uri.query(), file_exists) {
+            Some(Action::Redirect { status, location }) => {
+                return redirect(status, location, root_mount(host))
+            }
+            Some(Action::Rewrite { status, path }) => match relative_path(&path) {
+                Some(path) => (status, path, true),
+                None => {
+                    return error_page(StatusCode::NOT_FOUND, "", root_mount(host)).into_response()
+                }
+            },
+            None => (StatusCode::OK, path, false),
+        };
+
+    let (mount, path) = find_mount(host, &path);
+    let resolved = resolve_path(&path, mount);
+    let canonical = match resolved.canonical {
+        Some(canonical) => Some(format!("{}{}", mount.prefix, canonical)),
+        // Mounted directories are always served with a trailing slash
+        None if path.is_empty() && !request_path.ends_with('/') => {
+            Some(format!("{}/", mount.prefix))
+        }
+        None => None,
+    };
+    if let (Some(canonical), false) = (canonical, rewritten) {
+        let location = match uri.query() {
+            Some(query) => format!("{}?{}", canonical, query),
+            None => canonical,
+        };
+
+        return redirect(StatusCode::MOVED_PERMANENTLY, location, mount);
+    }
+
+    let mut res = handler(resolved.file, mount).await;
+    if res.0 == StatusCode::NOT_FOUND && mount.spa && Path::new(&path).extension().is_none() {
+        res = handler(index_file(mount), mount).await;
+    }
+    if res.0 == StatusCode::OK {
+        res.0 = status;
     }
+
+    res.into_response()
+}
+
+// Local locations are prefixed with the base path
+fn redirect(status: StatusCode, location: String, mount: &Mount) -> Response {
+    (
+        status,
+        SetHeader(String::from("Location"), with_base_path(location)),
+        set_cors_access(&mount.cors),
+    )
+        .into_response()
 }
 
-fn root_serve() -> String {
-    let file_exists = |file: &str| PROJECT_DIR.get_file(file).is_some();
+struct ResolvedPath {
+    file: String,
+    // The path the request should be redirected to for clean URLs
+    canonical: Option<String>,
+}
+
+// Maps the request path to a file of the mount. With clean URLs, `/about` is served from
+// `about.html` or `about/index.html` and `/about.html` is redirected to `/about`.
+fn resolve_path(path: &str, mount: &Mount) -> ResolvedPath {
+    if path.is_empty() {
+        return ResolvedPath {
+            file: index_file(mount),
+            canonical: None,
+        };
+    }
+    if !CLEAN_URLS {
+        return ResolvedPath {
+            file: String::from(path),
+            canonical: None,
+        };
+    }
+
+    let is_file = |path: &str| mount.dir.get_file(path).is_some();
+    let page = |file: String, dir: bool| {
+        let trimmed = path.trim_end_matches('/');
+        let with_slash = match TRAILING_SLASH {
+            Some(TrailingSlash::Always) => true,
+            Some(TrailingSlash::Never) => false,
+            None if dir => true,
+            None => path.ends_with('/'),
+        };
+        let canonical = if with_slash {
+            format!("/{}/", trimmed)
+        } else {
+            format!("/{}", trimmed)
+        };
+
+        ResolvedPath {
+            file,
+            canonical: (canonical != format!("/{}", path)).then_some(canonical),
+        }
+    };
+
+    let trimmed = path.trim_end_matches('/');
+    if let Some(stem) = trimmed.strip_suffix(".html") {
+        if is_file(trimmed) {
+            let (clean, dir) = match stem.strip_suffix("index") {
+                Some(dir) if dir.is_empty() || dir.ends_with('/') => {
+                    (dir.trim_end_matches('/'), true)
+                }
+                _ => (stem, false),
+            };
+            let canonical = match (clean.is_empty(), dir, &TRAILING_SLASH) {
+                (true, _, _) => String::from("/"),
+                (false, true, Some(TrailingSlash::Never)) => format!("/{}", clean),
+                (false, true, _) => format!("/{}/", clean),
+                (false, false, Some(TrailingSlash::Always)) => format!("/{}/", clean),
+                (false, false, _) => format!("/{}", clean),
+            };
+
+            return ResolvedPath {
+                file: String::from(trimmed),
+                canonical: Some(canonical),
+            };
+        }
+    }
+    if !path.ends_with('/') && is_file(path) {
+        return ResolvedPath {
+            file: String::from(path),
+            canonical: None,
+        };
+    }
+    let html_file = format!("{}.html", trimmed);
+    if is_file(&html_file) {
+        return page(html_file, false);
+    }
+    for index_file in INDEX_FILES {
+        let index_file = format!("{}/{}", trimmed, index_file);
+        if is_file(&index_file) {
+            return page(index_file, true);
+        }
+    }
+
+    ResolvedPath {
+        file: String::from(path),
+        canonical: None,
+    }
+}
+
+fn root_serve(dir: &Dir) -> String {
+    let file_exists = |file: &str| dir.get_file(file).is_some();
     let mut out = None;
     for index_file in INDEX_FILES {
         if file_exists(index_file) {
@@ -138,58 +796,478 @@ fn root_serve() -> String {
     String::from(out.unwrap())
 }
 
-async fn addr_is_available_or_exit(addr: &SocketAddr) {
-    let listener = tokio_net::TcpListener::bind(addr).await;
-    match listener {
-        Ok(_) => {}
-        Err(err) => {
-            unrecoverable_clap_error(format!(
-                "Unable to bind on: {}! Reason: {}.",
-                addr.to_string(),
-                err.to_string()
-            ));
+// IPv6 sockets accept IPv4 connections as well on most systems, unless an IPv4 address with the
+// same port is listened on too
+fn bind(addr: SocketAddr) -> Result<TcpListener, String> {
+    let only_v6 = addr.is_ipv6()
+        && ADDRS.iter().any(|other| match SocketAddr::from_str(other) {
+            Ok(other) => other.is_ipv4() && other.port() == addr.port(),
+            Err(_) => false,
+        });
+    let bind = || -> std::io::Result<TcpListener> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        if addr.is_ipv6() {
+            socket.set_only_v6(only_v6)?;
         }
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+
+        Ok(socket.into())
     };
+
+    bind().map_err(|err| format!("Unable to bind on: {}! Reason: {}.", addr, err))
 }
 
-pub async fn run() {
-    let root_serve = root_serve();
-    let app = Router::new()
-        .route("/", get(move || handler(root_serve)))
-        .route(
-            "/:filename",
-            get(move |AxumPath(path): AxumPath<String>| handler(path)),
-        );
-
-    tokio::spawn(async {
-        let mut i = 0;
-        loop {
-            tokio_time::sleep(Duration::from_secs(2)).await;
-
-            let addr = ADDR.to_string();
-            let url = format!("http://{}", &addr);
-            if let Ok(resp) = reqwest::get(url).await {
-                if resp.status() == 200 || resp.status() == 404 {
-                    println!("Server has started. Listening on {}.", &addr);
-
-                    break;
+mod timeout {
+    use hyper::server::accept::Accept;
+    use std::{
+        future::Future,
+        io,
+        pin::Pin,
+        sync::atomic::{AtomicUsize, Ordering},
+        task::{Context, Poll},
+        time::Duration,
+    };
+    use tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+        time::{self as tokio_time, Instant, Sleep},
+    };
+
+    const REJECTED: &[u8] = b"HTTP/1.1 429 Too Many Requests\r\nRetry-After: 1\r\nContent-Type: text/plain\r\nContent-Length: 21\r\nConnection: close\r\n\r\nToo many connections\n";
+
+    // A timeout of 0 seconds disables it
+    fn timeout(secs: u64) -> Option<Duration> {
+        (secs > 0).then(|| Duration::from_secs(secs))
+    }
+
+    // Whether the headers of a request arrived
+    async fn read_head<S: AsyncRead + Unpin>(stream: &mut S) -> bool {
+        let mut head = vec![];
+        let mut buf = [0; 1024];
+        while head.len() < super::MAX_HEADER_SIZE {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => return false,
+                Ok(read) => head.extend_from_slice(&buf[..read]),
+            }
+            if head.windows(4).any(|end| end == b"\r\n\r\n") {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    // Connections over the limit get a 429 once their request arrived, then they're closed. They
+    // aren't kept waiting for long, even without a header read timeout.
+    async fn reject<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S) {
+        let wait = timeout(super::HEADER_READ_TIMEOUT).unwrap_or(Duration::from_secs(10));
+        if let Ok(true) = tokio_time::timeout(wait, read_head(&mut stream)).await {
+            let _ = tokio_time::timeout(wait, stream.write_all(REJECTED)).await;
+            let _ = stream.shutdown().await;
+        }
+    }
+
+    // The open connections of all listeners
+    static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+    // Frees a connection slot when dropped
+    struct Slot;
+    impl Drop for Slot {
+        fn drop(&mut self) {
+            CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    // Closes connections that make no progress. The idle timer restarts on every read or write,
+    // and the write timer runs while the client doesn't accept the response.
+    pub struct TimeoutStream<S> {
+        stream: S,
+        idle: Option<Pin<Box<Sleep>>>,
+        write: Option<Pin<Box<Sleep>>>,
+        _slot: Slot,
+    }
+
+    impl<S> TimeoutStream<S> {
+        fn reset_idle(&mut self) {
+            if let (Some(timeout), Some(idle)) = (timeout(super::IDLE_TIMEOUT), &mut self.idle) {
+                idle.as_mut().reset(Instant::now() + timeout);
+            }
+        }
+
+        fn poll_write_timeout<T>(
+            &mut self,
+            cx: &mut Context<'_>,
+            poll: Poll<io::Result<T>>,
+        ) -> Poll<io::Result<T>> {
+            if poll.is_ready() {
+                self.write = None;
+                self.reset_idle();
+                return poll;
+            }
+            let timeout = match timeout(super::WRITE_TIMEOUT) {
+                Some(timeout) => timeout,
+                None => return poll,
+            };
+
+            let write = self
+                .write
+                .get_or_insert_with(|| Box::pin(tokio_time::sleep(timeout)));
+            match write.as_mut().poll(cx) {
+                Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "The client stopped receiving",
+                ))),
+                Poll::Pending => Poll::Pending,
+            }
+        }
+    }
+
+    impl<S: AsyncRead + Unpin> AsyncRead for TimeoutStream<S> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let this = self.get_mut();
+            match Pin::new(&mut this.stream).poll_read(cx, buf) {
+                Poll::Ready(result) => {
+                    this.reset_idle();
+                    Poll::Ready(result)
+                }
+                Poll::Pending => match &mut this.idle {
+                    Some(idle) => idle.as_mut().poll(cx).map(|_| {
+                        Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "The connection was idle",
+                        ))
+                    }),
+                    None => Poll::Pending,
+                },
+            }
+        }
+    }
+
+    impl<S: AsyncWrite + Unpin> AsyncWrite for TimeoutStream<S> {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            let poll = Pin::new(&mut this.stream).poll_write(cx, buf);
+
+            this.poll_write_timeout(cx, poll)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            let this = self.get_mut();
+            let poll = Pin::new(&mut this.stream).poll_flush(cx);
+
+            this.poll_write_timeout(cx, poll)
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+        }
+    }
+
+    // Applies the timeouts and the connection limit to the accepted connections
+    pub struct TimeoutAccept<A> {
+        accept: A,
+    }
+
+    impl<A> TimeoutAccept<A> {
+        pub fn new(accept: A) -> Self {
+            Self { accept }
+        }
+    }
+
+    impl<A> Accept for TimeoutAccept<A>
+    where
+        A: Accept + Unpin,
+        A::Conn: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        type Conn = TimeoutStream<A::Conn>;
+        type Error = A::Error;
+
+        fn poll_accept(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+            let this = self.get_mut();
+            loop {
+                let stream = match Pin::new(&mut this.accept).poll_accept(cx) {
+                    Poll::Ready(Some(Ok(stream))) => stream,
+                    Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+                    Poll::Ready(None) => return Poll::Ready(None),
+                    Poll::Pending => return Poll::Pending,
+                };
+
+                let open = CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+                let slot = Slot;
+                if super::MAX_CONNECTIONS > 0 && open >= super::MAX_CONNECTIONS {
+                    drop(slot);
+                    tokio::spawn(reject(stream));
+                    continue;
                 }
+
+                return Poll::Ready(Some(Ok(TimeoutStream {
+                    stream,
+                    idle: timeout(super::IDLE_TIMEOUT)
+                        .map(|timeout| Box::pin(tokio_time::sleep(timeout))),
+                    write: None,
+                    _slot: slot,
+                })));
             }
+        }
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use hyper::server::accept::Accept;
+    use std::{
+        fs::{self, DirBuilder, Permissions},
+        io,
+        os::unix::{
+            fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+            net::UnixStream as StdUnixStream,
+        },
+        path::Path,
+        pin::Pin,
+        process,
+        task::{Context, Poll},
+    };
+    use tokio::net::{UnixListener, UnixStream};
 
-            if i > 60 {
-                unrecoverable_clap_error("Timout exceeded! Server failed to start.");
+    // Accepts the connections of a Unix domain socket
+    pub struct UnixAccept(pub UnixListener);
+    impl Accept for UnixAccept {
+        type Conn = UnixStream;
+        type Error = io::Error;
+
+        fn poll_accept(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+            match self.0.poll_accept(cx) {
+                Poll::Ready(Ok((stream, _))) => Poll::Ready(Some(Ok(stream))),
+                Poll::Ready(Err(err)) => Poll::Ready(Some(Err(err))),
+                Poll::Pending => Poll::Pending,
             }
+        }
+    }
 
-            i += 1;
+    // Removes a socket file left behind by a previous run. Sockets that still accept connections are
+    // in use by another process and kept.
+    fn remove_stale_socket(path: &Path) -> Result<(), String> {
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(_) => return Ok(()),
+        };
+        if !metadata.file_type().is_socket() {
+            return Err(format!(
+                "Unable to bind on: {}! Reason: The file exists and is not a socket.",
+                path.display()
+            ));
         }
-    });
+        if StdUnixStream::connect(path).is_ok() {
+            return Err(format!(
+                "Unable to bind on: {}! Reason: The socket is in use by another process.",
+                path.display()
+            ));
+        }
+
+        fs::remove_file(path).map_err(|err| {
+            format!(
+                "Unable to remove the stale socket: {}! Reason: {}.",
+                path.display(),
+                err
+            )
+        })
+    }
+
+    // With a mode, the socket is bound in a private directory next to it and only moved in place once
+    // it has its mode, so nobody can connect while it still has the permissions of the umask
+    pub fn bind(path: &Path, mode: Option<u32>) -> Result<UnixAccept, String> {
+        remove_stale_socket(path)?;
+        let mode = match mode {
+            Some(mode) => mode,
+            None => {
+                return UnixListener::bind(path).map(UnixAccept).map_err(|err| {
+                    format!("Unable to bind on: {}! Reason: {}.", path.display(), err)
+                })
+            }
+        };
+
+        let dir = path.with_file_name(format!(".sfhs-{}", process::id()));
+        DirBuilder::new().mode(0o700).create(&dir).map_err(|err| {
+            format!(
+                "Unable to create a directory next to: {}! Reason: {}.",
+                path.display(),
+                err
+            )
+        })?;
+        let private_path = dir.join(path.file_name().unwrap_or_default());
+        let listener = UnixListener::bind(&private_path)
+            .map_err(|err| format!("Unable to bind on: {}! Reason: {}.", path.display(), err))
+            .and_then(|listener| {
+                fs::set_permissions(&private_path, Permissions::from_mode(mode)).map_err(
+                    |err| {
+                        format!(
+                            "Unable to set the permissions of: {}! Reason: {}.",
+                            path.display(),
+                            err
+                        )
+                    },
+                )?;
+                fs::rename(&private_path, path).map_err(|err| {
+                    format!("Unable to bind on: {}! Reason: {}.", path.display(), err)
+                })?;
+
+                Ok(listener)
+            });
+        let _ = fs::remove_file(&private_path);
+        let _ = fs::remove_dir(&dir);
+
+        listener.map(UnixAccept)
+    }
+}
 
-    addr_is_available_or_exit(&ADDR).await;
-    let server = axum::Server::bind(&ADDR)
-        .serve(app.into_make_service())
-        .await;
-    if let Err(err) = server {
-        unrecoverable_clap_error(err);
+// The module of the server, written next to this file when producing the binary
+#[cfg(unix)]
+#[allow(dead_code)]
+#[path = "../../src/systemd.rs"]
+mod systemd;
+
+// The embedded files can always be read, so the binary is ready as soon as it's alive
+async fn health() -> Response {
+    (
+        SetHeader(
+            String::from("Content-Type"),
+            String::from("application/json"),
+        ),
+        SetHeader(String::from("Cache-Control"), String::from("no-store")),
+        format!(
+            "{{\"status\":\"ok\",\"root_readable\":true,\"version\":{:?},\"built_at\":{}}}",
+            BUILD_VERSION, BUILT_AT
+        ),
+    )
+        .into_response()
+}
+
+fn server<A: Accept + Unpin>(accept: A) -> Builder<timeout::TimeoutAccept<A>> {
+    let builder = axum::Server::builder(timeout::TimeoutAccept::new(accept))
+        .http1_max_buf_size(MAX_HEADER_SIZE);
+    if HEADER_READ_TIMEOUT == 0 {
+        return builder;
+    }
+
+    builder.http1_header_read_timeout(Duration::from_secs(HEADER_READ_TIMEOUT))
+}
+
+pub async fn run() {
+    let mut app = Router::new()
+        .route("/", get(serve).fallback(method_not_allowed))
+        .route("/*path", get(serve).fallback(method_not_allowed));
+    if HEALTH_CHECKS {
+        app = app
+            .route(HEALTH_PATH, get(health))
+            .route(READY_PATH, get(health));
+    }
+    if !HEADER_RULES.is_empty() {
+        app = app.layer(middleware::from_fn(set_headers));
+    }
+
+    // Sockets passed by systemd are used instead of binding the same address
+    #[cfg(unix)]
+    let mut activated = systemd::listeners();
+    let mut listeners = vec![];
+    for addr in ADDRS {
+        let addr = SocketAddr::from_str(addr).unwrap();
+        #[cfg(unix)]
+        if let Some(listener) = activated.take_tcp(addr) {
+            listeners.push((addr, Ok(listener)));
+            continue;
+        }
+
+        listeners.push((addr, bind(addr)));
+    }
+    // The other sockets passed by systemd are served as well
+    #[cfg(unix)]
+    for (addr, listener) in activated.tcp.drain(..) {
+        listeners.push((addr, Ok(listener)));
+    }
+
+    let mut names = vec![];
+    let mut tasks = vec![];
+    for (addr, listener) in listeners {
+        let incoming = listener.and_then(|listener| {
+            let listener =
+                tokio::net::TcpListener::from_std(listener).map_err(|err| err.to_string())?;
+
+            AddrIncoming::from_listener(listener).map_err(|err| err.to_string())
+        });
+        let incoming = match incoming {
+            Ok(incoming) => incoming,
+            Err(err) => return unrecoverable_clap_error(err),
+        };
+        names.push(format!("http://{}", addr));
+        tasks.push(tokio::spawn(
+            server(incoming).serve(app.clone().into_make_service()),
+        ));
+    }
+    #[cfg(unix)]
+    {
+        let mut unix_listeners = vec![];
+        if let Some(path) = UNIX_SOCKET {
+            let listener = match activated.take_unix(Path::new(path)) {
+                Some(listener) => tokio::net::UnixListener::from_std(listener)
+                    .map(unix::UnixAccept)
+                    .map_err(|err| err.to_string()),
+                None => unix::bind(Path::new(path), UNIX_MODE),
+            };
+            unix_listeners.push((String::from(path), listener));
+        }
+        for (path, listener) in activated.unix.drain(..) {
+            let name = match path {
+                Some(path) => path.display().to_string(),
+                None => String::from("an unnamed Unix socket"),
+            };
+            let listener = tokio::net::UnixListener::from_std(listener)
+                .map(unix::UnixAccept)
+                .map_err(|err| err.to_string());
+            unix_listeners.push((name, listener));
+        }
+
+        for (name, listener) in unix_listeners {
+            let listener = match listener {
+                Ok(listener) => listener,
+                Err(err) => return unrecoverable_clap_error(err),
+            };
+            names.push(name);
+            tasks.push(tokio::spawn(
+                server(listener).serve(app.clone().into_make_service()),
+            ));
+        }
+    }
+
+    println!("Server has started. Listening on {}.", names.join(", "));
+    #[cfg(unix)]
+    {
+        systemd::notify("READY=1");
+        systemd::spawn_watchdog();
+        systemd::spawn_shutdown_handler();
+    }
+    for task in tasks {
+        match task.await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => unrecoverable_clap_error(err),
+            Err(err) => unrecoverable_clap_error(err),
+        }
     }
 }
 
@@ -0,0 +1,409 @@
+use crate::{
+    cli::{self, Dotfiles, Symlinks},
+    mounts::Mount,
+};
+use axum::http::StatusCode;
+use once_cell::sync::Lazy;
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+pub const IGNORE_FILE: &str = ".sfhsignore";
+
+// Never served, whatever the dotfile policy is: version control data, credentials, editor
+// leftovers, unfinished uploads and the build cache of the single binary
+const DENIED: &[&str] = &[
+    ".git/",
+    ".svn/",
+    ".hg/",
+    ".ssh/",
+    ".aws/",
+    ".env",
+    ".env.*",
+    ".htpasswd",
+    ".htaccess",
+    ".netrc",
+    ".npmrc",
+    ".pypirc",
+    ".DS_Store",
+    "*.swp",
+    "*.swo",
+    "*~",
+    "#*#",
+    IGNORE_FILE,
+    ".sfhs-upload-*",
+    "/.static-file-http-server-cache/",
+];
+
+static DENY_LIST: Lazy<Ignore> = Lazy::new(|| Ignore::parse(&DENIED.join("\n")));
+
+#[derive(Debug, PartialEq)]
+pub enum Access {
+    Allowed,
+    // Answered with '403 Forbidden'
+    Forbidden,
+    // Answered with '404 Not Found' as if it didn't exist
+    Hidden,
+}
+impl Access {
+    // The status to refuse the request with, if it's refused
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            Self::Allowed => None,
+            Self::Forbidden => Some(StatusCode::FORBIDDEN),
+            Self::Hidden => Some(StatusCode::NOT_FOUND),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Pattern {
+    glob: Vec<char>,
+    negated: bool,
+    dir_only: bool,
+    // Patterns containing a slash match the whole path, the others match any single file name
+    anchored: bool,
+}
+
+// The patterns of an ignore file, following the gitignore syntax
+#[derive(Debug, Clone, Default)]
+pub struct Ignore {
+    patterns: Vec<Pattern>,
+}
+
+impl Ignore {
+    fn parse(content: &str) -> Self {
+        let mut patterns = vec![];
+        for line in content.lines() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (negated, line) = match line.strip_prefix('!') {
+                Some(line) => (true, line),
+                None => (false, line),
+            };
+            // `\#` and `\!` start patterns with a literal '#' or '!'
+            let line = line
+                .strip_prefix('\\')
+                .filter(|line| line.starts_with(['#', '!']))
+                .unwrap_or(line);
+            let (dir_only, line) = match line.strip_suffix('/') {
+                Some(line) => (true, line),
+                None => (false, line),
+            };
+            let anchored = line.contains('/');
+            let line = line.strip_prefix('/').unwrap_or(line);
+            if line.is_empty() {
+                continue;
+            }
+
+            patterns.push(Pattern {
+                glob: line.chars().collect(),
+                negated,
+                dir_only,
+                anchored,
+            });
+        }
+
+        Self { patterns }
+    }
+
+    // Reads the ignore file at the root of a served directory, if there's one
+    pub fn load(dir: &Path) -> Result<Self, String> {
+        let path = dir.join(IGNORE_FILE);
+        match fs::read_to_string(&path) {
+            Ok(content) => Ok(Self::parse(&content)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(format!(
+                "Unable to read the ignore file '{}': {}",
+                path.display(),
+                err
+            )),
+        }
+    }
+
+    // The last matching pattern decides
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        let path: Vec<char> = path.chars().collect();
+        let name_start = path.iter().rposition(|c| *c == '/').map_or(0, |i| i + 1);
+
+        let mut ignored = false;
+        for pattern in &self.patterns {
+            if pattern.dir_only && !is_dir {
+                continue;
+            }
+            let text = if pattern.anchored {
+                &path[..]
+            } else {
+                &path[name_start..]
+            };
+            if glob(&pattern.glob, text) {
+                ignored = !pattern.negated;
+            }
+        }
+
+        ignored
+    }
+
+    // Files inside of an ignored directory are ignored as well, like with git
+    pub fn is_ignored(&self, path: &str, is_dir: bool) -> bool {
+        let path = path.trim_matches('/');
+        if self.patterns.is_empty() || path.is_empty() {
+            return false;
+        }
+
+        path.match_indices('/')
+            .any(|(i, _)| self.matches(&path[..i], true))
+            || self.matches(path, is_dir)
+    }
+}
+
+// Matches a gitignore style glob. '*' and '?' stop at slashes while '**' crosses them.
+fn glob(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') if pattern.get(1) == Some(&'*') => {
+            let rest = &pattern[2..];
+            match rest.strip_prefix(&['/']) {
+                // `a/**/b` matches `a/b` as well
+                Some(rest) => {
+                    glob(rest, text)
+                        || (0..text.len()).any(|i| text[i] == '/' && glob(rest, &text[i + 1..]))
+                }
+                None => (0..=text.len()).any(|i| glob(rest, &text[i..])),
+            }
+        }
+        Some('*') => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != '/')
+            .any(|i| glob(&pattern[1..], &text[i..])),
+        Some('?') => !text.is_empty() && text[0] != '/' && glob(&pattern[1..], &text[1..]),
+        Some('[') => match class(&pattern[1..], text.first().copied()) {
+            Some((len, matches)) => matches && glob(&pattern[len + 1..], &text[1..]),
+            // Unclosed brackets are taken literally
+            None => text.first() == Some(&'[') && glob(&pattern[1..], &text[1..]),
+        },
+        Some('\\') if pattern.len() > 1 => {
+            text.first() == Some(&pattern[1]) && glob(&pattern[2..], &text[1..])
+        }
+        Some(c) => text.first() == Some(c) && glob(&pattern[1..], &text[1..]),
+    }
+}
+
+// Parses a bracket expression like `[a-z]` or `[!0-9]` following the opening bracket. Returns
+// its length including the closing bracket and whether the character is part of it, or nothing
+// when the bracket isn't closed.
+fn class(pattern: &[char], c: Option<char>) -> Option<(usize, bool)> {
+    let (negated, start) = match pattern.first() {
+        Some('!') | Some('^') => (true, 1),
+        _ => (false, 0),
+    };
+    // A closing bracket right at the start is part of the set
+    let end = start + 1 + pattern.get(start + 1..)?.iter().position(|c| *c == ']')?;
+    let set = &pattern[start..end];
+    let c = match c {
+        Some(c) if c != '/' => c,
+        _ => return Some((end + 1, false)),
+    };
+
+    let mut found = false;
+    let mut i = 0;
+    while i < set.len() {
+        if i + 2 < set.len() && set[i + 1] == '-' {
+            found |= set[i] <= c && c <= set[i + 2];
+            i += 3;
+        } else {
+            found |= set[i] == c;
+            i += 1;
+        }
+    }
+
+    Some((end + 1, found != negated))
+}
+
+// Dotfiles are names starting with a dot, except for `.well-known` which holds public metadata
+fn is_dotfile(path: &str) -> bool {
+    path.split('/')
+        .any(|segment| segment.starts_with('.') && segment != ".well-known" && segment != ".")
+}
+
+// Whether the symlinks between the mount and the path may be followed. The mount itself may be a
+// symlink since it was chosen on purpose.
+pub fn symlinks_allowed(mount: &Mount, path: &str, args: &cli::Args) -> bool {
+    match args.symlinks {
+        Symlinks::Follow => true,
+        Symlinks::WithinRoot => {
+            let root = match fs::canonicalize(&mount.path) {
+                Ok(root) => root,
+                Err(_) => return false,
+            };
+            // Files that don't exist yet, like uploads, are checked by their closest existing
+            // parent directory
+            mount
+                .path
+                .join(path)
+                .ancestors()
+                .find_map(|ancestor| fs::canonicalize(ancestor).ok())
+                .is_some_and(|target| target.starts_with(root))
+        }
+        Symlinks::Deny => {
+            let mut current = mount.path.clone();
+            for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+                current.push(segment);
+                match fs::symlink_metadata(&current) {
+                    Ok(metadata) if metadata.file_type().is_symlink() => return false,
+                    Ok(_) => {}
+                    Err(_) => break,
+                }
+            }
+
+            true
+        }
+    }
+}
+
+// Decides whether a path relative to the mount may be served
+pub fn check(mount: &Mount, path: &str, is_dir: bool, args: &cli::Args) -> Access {
+    if DENY_LIST.is_ignored(path, is_dir) || mount.ignore.is_ignored(path, is_dir) {
+        return Access::Hidden;
+    }
+    if !symlinks_allowed(mount, path, args) {
+        return Access::Forbidden;
+    }
+    if !is_dotfile(path) {
+        return Access::Allowed;
+    }
+
+    match args.dotfiles {
+        Dotfiles::Allow => Access::Allowed,
+        Dotfiles::Deny => Access::Forbidden,
+        Dotfiles::Ignore => Access::Hidden,
+    }
+}
+
+// Copies the files of a mount that may be served, so only those get embedded into the single
+// binary
+pub fn copy_allowed(mount: &Mount, dest: &Path, args: &cli::Args) -> std::io::Result<()> {
+    // `parents` holds the canonical paths of the directories being copied, so symlinks pointing
+    // back at one of them aren't followed forever
+    fn copy_dir(
+        mount: &Mount,
+        dir: &str,
+        dest: &Path,
+        parents: &mut Vec<PathBuf>,
+        args: &cli::Args,
+    ) -> std::io::Result<()> {
+        let canonical = fs::canonicalize(mount.path.join(dir))?;
+        if parents.contains(&canonical) {
+            return Ok(());
+        }
+        parents.push(canonical);
+
+        fs::create_dir_all(dest.join(dir))?;
+        for entry in fs::read_dir(mount.path.join(dir))? {
+            let entry = entry?;
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            let path = match dir {
+                "" => name,
+                dir => format!("{}/{}", dir, name),
+            };
+            // Follows the symlinks the server follows, dangling ones are skipped like the server
+            // can't serve them either
+            let metadata = match fs::metadata(entry.path()) {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            if check(mount, &path, metadata.is_dir(), args) != Access::Allowed {
+                continue;
+            }
+
+            if metadata.is_dir() {
+                copy_dir(mount, &path, dest, parents, args)?;
+            } else {
+                fs::copy(entry.path(), dest.join(&path))?;
+            }
+        }
+
+        parents.pop();
+        Ok(())
+    }
+
+    if dest.exists() {
+        fs::remove_dir_all(dest)?;
+    }
+
+    copy_dir(mount, "", dest, &mut vec![], args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob_matches(pattern: &str, text: &str) -> bool {
+        let pattern: Vec<char> = pattern.chars().collect();
+        let text: Vec<char> = text.chars().collect();
+        glob(&pattern, &text)
+    }
+
+    #[test]
+    fn stars() {
+        assert!(glob_matches("*.log", "debug.log"));
+        assert!(!glob_matches("*.log", "logs/debug.log"));
+        assert!(glob_matches("logs/**", "logs/a/b.log"));
+        assert!(glob_matches("a/**/b", "a/b"));
+        assert!(glob_matches("a/**/b", "a/x/y/b"));
+        assert!(!glob_matches("a/**/b", "ab"));
+        assert!(glob_matches("**/build", "src/build"));
+    }
+
+    #[test]
+    fn question_marks_and_classes() {
+        assert!(glob_matches("file?.txt", "file1.txt"));
+        assert!(!glob_matches("file?.txt", "file/.txt"));
+        assert!(glob_matches("[a-c]*.txt", "b.txt"));
+        assert!(!glob_matches("[!a-c]*.txt", "b.txt"));
+        assert!(glob_matches("[]]", "]"));
+        assert!(glob_matches("[abc", "[abc"));
+        assert!(glob_matches("\\*", "*"));
+        assert!(!glob_matches("\\*", "a"));
+    }
+
+    #[test]
+    fn ignore_file() {
+        let ignore = Ignore::parse("# comment\n*.log\n!keep.log\nbuild/\n/secret.txt\n\\#notes\n");
+
+        assert!(ignore.is_ignored("debug.log", false));
+        assert!(ignore.is_ignored("logs/debug.log", false));
+        assert!(!ignore.is_ignored("keep.log", false));
+        assert!(ignore.is_ignored("build", true));
+        assert!(!ignore.is_ignored("build", false));
+        assert!(ignore.is_ignored("build/app.js", false));
+        assert!(ignore.is_ignored("secret.txt", false));
+        assert!(!ignore.is_ignored("docs/secret.txt", false));
+        assert!(ignore.is_ignored("#notes", false));
+        assert!(!ignore.is_ignored("", true));
+    }
+
+    #[test]
+    fn deny_list() {
+        assert!(DENY_LIST.is_ignored(".git/config", false));
+        assert!(DENY_LIST.is_ignored("app/.env.production", false));
+        assert!(DENY_LIST.is_ignored(".static-file-http-server-cache", true));
+        assert!(!DENY_LIST.is_ignored("docs/.static-file-http-server-cache", true));
+        assert!(!DENY_LIST.is_ignored(".well-known/security.txt", false));
+    }
+
+    #[test]
+    fn dotfiles() {
+        assert!(is_dotfile(".env"));
+        assert!(is_dotfile("a/.hidden/b"));
+        assert!(!is_dotfile(".well-known/acme-challenge/token"));
+        assert!(!is_dotfile("a/b.c"));
+    }
+}
@@ -0,0 +1,87 @@
+use crate::{cli, limiter::ClientInfo, timefmt};
+use axum::{
+    body::HttpBody,
+    extract::ConnectInfo,
+    http::{header, HeaderMap, Request},
+    middleware::Next,
+    response::Response,
+};
+use std::time::SystemTime;
+
+// Quoted, with the quotes and backslashes inside escaped
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn header_value(headers: &HeaderMap, name: header::HeaderName) -> String {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map_or_else(|| String::from("-"), quote)
+}
+
+// Health checks are left out like they are from the metrics, unless they're counted
+fn is_health_check(path: &str, args: &cli::Args) -> bool {
+    args.health_checks
+        && !args.count_health_checks
+        && (path == args.health_path || path == args.ready_path)
+}
+
+// Writes a line in the Combined Log Format for every response, with the subject of the verified
+// client certificate as the user
+pub async fn middleware<B>(req: Request<B>, next: Next<B>, args: &'static cli::Args) -> Response {
+    if is_health_check(req.uri().path(), args) {
+        return next.run(req).await;
+    }
+    let (addr, user) = match req.extensions().get::<ConnectInfo<ClientInfo>>() {
+        Some(ConnectInfo(client)) => (
+            client
+                .addr
+                .map_or_else(|| String::from("unix:"), |addr| addr.ip().to_string()),
+            client
+                .subject
+                .as_deref()
+                .map_or_else(|| String::from("-"), quote),
+        ),
+        None => (String::from("-"), String::from("-")),
+    };
+    let time = timefmt::strftime("%d/%b/%Y:%H:%M:%S %z", SystemTime::now());
+    let request = format!(
+        "{} {} {:?}",
+        req.method(),
+        req.uri()
+            .path_and_query()
+            .map_or("/", |path_and_query| path_and_query.as_str()),
+        req.version()
+    );
+    let referer = header_value(req.headers(), header::REFERER);
+    let user_agent = header_value(req.headers(), header::USER_AGENT);
+
+    let res = next.run(req).await;
+    // Hyper only sets `Content-Length` after the middleware, from the size of the body
+    let size = res
+        .body()
+        .size_hint()
+        .exact()
+        .map(|size| size.to_string())
+        .or_else(|| {
+            res.headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from)
+        })
+        .unwrap_or_else(|| String::from("-"));
+    println!(
+        "{} - {} [{}] {} {} {} {} {}",
+        addr,
+        user,
+        time,
+        quote(&request),
+        res.status().as_u16(),
+        size,
+        referer,
+        user_agent
+    );
+
+    res
+}
@@ -0,0 +1,345 @@
+use crate::{
+    config::{AcmeChallenge, AcmeConfig},
+    https, x509,
+};
+use axum::{
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, NewAccount,
+    NewOrder, Order, OrderStatus,
+};
+use once_cell::sync::Lazy;
+use rcgen::{CertificateParams, CustomExtension, DistinguishedName};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{BufReader, Write},
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::time as tokio_time;
+use tokio_rustls::rustls::{
+    server::{ClientHello, ResolvesServerCert, WantsServerCert},
+    sign::{self, CertifiedKey},
+    Certificate, ConfigBuilder, PrivateKey, ServerConfig,
+};
+
+// The ALPN protocol of TLS-ALPN-01 challenges, RFC 8737
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+const CERT_FILE: &str = "cert.pem";
+const KEY_FILE: &str = "key.pem";
+// Failed orders are retried after a while, so rate limits of the ACME server aren't hit
+const RETRY_AFTER: Duration = Duration::from_secs(3600);
+
+static RESOLVER: Lazy<Arc<Resolver>> = Lazy::new(|| Arc::new(Resolver::default()));
+// Key authorizations of pending HTTP-01 challenges by token
+static HTTP_CHALLENGES: Lazy<Mutex<HashMap<String, String>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Hands out the current certificate, which gets swapped on renewal without restarting
+#[derive(Default)]
+struct Resolver {
+    cert: RwLock<Option<Arc<CertifiedKey>>>,
+    // Certificates of pending TLS-ALPN-01 challenges by domain
+    challenges: Mutex<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let is_challenge = client_hello
+            .alpn()
+            .is_some_and(|mut protocols| protocols.any(|protocol| protocol == ACME_TLS_ALPN));
+        if is_challenge {
+            let name = client_hello.server_name()?;
+            return self.challenges.lock().unwrap().get(name).cloned();
+        }
+
+        self.cert.read().unwrap().clone()
+    }
+}
+
+// The TLS config of the listeners serving the ACME certificate
+pub fn server_config(builder: ConfigBuilder<ServerConfig, WantsServerCert>) -> ServerConfig {
+    builder.with_cert_resolver(RESOLVER.clone())
+}
+
+// Answers HTTP-01 challenges on every listener, before the base path or any host applies
+pub async fn http_challenge<B>(req: Request<B>, next: Next<B>) -> Response {
+    let token = match req.uri().path().strip_prefix(https::ACME_CHALLENGE_PREFIX) {
+        Some(token) => token,
+        None => return next.run(req).await,
+    };
+
+    match HTTP_CHALLENGES.lock().unwrap().get(token) {
+        Some(key_authorization) => key_authorization.clone().into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+fn certified_key(certs: Vec<Certificate>, key: &PrivateKey) -> Result<Arc<CertifiedKey>, String> {
+    let key = sign::any_supported_type(key).map_err(|err| err.to_string())?;
+
+    Ok(Arc::new(CertifiedKey::new(certs, key)))
+}
+
+// Serves the stored certificate and returns its validity
+fn install(config: &AcmeConfig) -> Result<(u64, u64), String> {
+    let read = |name: &str| {
+        fs::File::open(config.cache_dir.join(name))
+            .map(BufReader::new)
+            .map_err(|err| err.to_string())
+    };
+    let certs = rustls_pemfile::certs(&mut read(CERT_FILE)?).map_err(|err| err.to_string())?;
+    let key = rustls_pemfile::pkcs8_private_keys(&mut read(KEY_FILE)?)
+        .map_err(|err| err.to_string())?
+        .pop()
+        .ok_or("No private key found")?;
+    let validity = certs
+        .first()
+        .and_then(|cert| x509::validity(cert))
+        .ok_or("No valid certificate found")?;
+
+    let certs = certs.into_iter().map(Certificate).collect();
+    *RESOLVER.cert.write().unwrap() = Some(certified_key(certs, &PrivateKey(key))?);
+
+    Ok(validity)
+}
+
+// Private keys are only readable by the owner
+fn write_private(path: &Path, contents: &str) -> std::io::Result<()> {
+    let temp = path.with_extension("tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(&temp)?.write_all(contents.as_bytes())?;
+
+    // Renamed in place, so a crash never leaves a half written file
+    fs::rename(temp, path)
+}
+
+// Accounts are stored per directory, so switching between Pebble and Let's Encrypt works
+async fn account(config: &AcmeConfig) -> Result<Account, String> {
+    let name: String = config
+        .directory
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let path = config.cache_dir.join(format!("account-{}.json", name));
+    if let Ok(json) = fs::read_to_string(&path) {
+        let credentials: AccountCredentials =
+            serde_json::from_str(&json).map_err(|err| err.to_string())?;
+        return Account::from_credentials(credentials).map_err(|err| err.to_string());
+    }
+
+    let contact: Vec<&str> = config.contact.iter().map(String::as_str).collect();
+    let account = Account::create(
+        &NewAccount {
+            contact: &contact,
+            terms_of_service_agreed: true,
+            only_return_existing: false,
+        },
+        &config.directory,
+    )
+    .await
+    .map_err(|err| err.to_string())?;
+    let json = serde_json::to_string(&account.credentials()).map_err(|err| err.to_string())?;
+    write_private(&path, &json).map_err(|err| err.to_string())?;
+
+    Ok(account)
+}
+
+// The self-signed certificate proving the control of a domain for TLS-ALPN-01
+fn challenge_certificate(domain: &str, digest: &[u8]) -> Result<Arc<CertifiedKey>, String> {
+    let mut params = CertificateParams::new(vec![String::from(domain)]);
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(digest)];
+    let cert = rcgen::Certificate::from_params(params).map_err(|err| err.to_string())?;
+    let der = cert.serialize_der().map_err(|err| err.to_string())?;
+
+    certified_key(
+        vec![Certificate(der)],
+        &PrivateKey(cert.serialize_private_key_der()),
+    )
+}
+
+// Waits with an increasing delay until the order leaves the pending and processing states
+async fn poll(order: &mut Order) -> Result<(), String> {
+    let mut delay = Duration::from_millis(500);
+    for _ in 0..10 {
+        tokio_time::sleep(delay).await;
+        let state = order.refresh().await.map_err(|err| err.to_string())?;
+        match state.status {
+            OrderStatus::Pending | OrderStatus::Processing => {
+                delay = (delay * 2).min(Duration::from_secs(8))
+            }
+            OrderStatus::Invalid => {
+                return Err(match &state.error {
+                    Some(problem) => problem.to_string(),
+                    None => String::from("The order became invalid"),
+                })
+            }
+            OrderStatus::Ready | OrderStatus::Valid => return Ok(()),
+        }
+    }
+
+    Err(String::from("The order timed out"))
+}
+
+// Proves the control of the domains, then stores and serves the new certificate
+async fn order(config: &AcmeConfig) -> Result<(), String> {
+    let account = account(config).await?;
+    let identifiers: Vec<Identifier> = config
+        .domains
+        .iter()
+        .map(|domain| Identifier::Dns(domain.clone()))
+        .collect();
+    let mut order = account
+        .new_order(&NewOrder {
+            identifiers: &identifiers,
+        })
+        .await
+        .map_err(|err| err.to_string())?;
+
+    let challenge_type = match config.challenge {
+        AcmeChallenge::Http01 => ChallengeType::Http01,
+        AcmeChallenge::TlsAlpn01 => ChallengeType::TlsAlpn01,
+    };
+    let authorizations = order
+        .authorizations()
+        .await
+        .map_err(|err| err.to_string())?;
+    let mut ready = vec![];
+    for authorization in &authorizations {
+        if let AuthorizationStatus::Valid = authorization.status {
+            continue;
+        }
+        let Identifier::Dns(domain) = &authorization.identifier;
+        let challenge = authorization
+            .challenges
+            .iter()
+            .find(|challenge| challenge.r#type == challenge_type)
+            .ok_or_else(|| format!("No {:?} challenge offered for {}", challenge_type, domain))?;
+
+        let key_authorization = order.key_authorization(challenge);
+        match config.challenge {
+            AcmeChallenge::Http01 => {
+                HTTP_CHALLENGES.lock().unwrap().insert(
+                    challenge.token.clone(),
+                    String::from(key_authorization.as_str()),
+                );
+            }
+            AcmeChallenge::TlsAlpn01 => {
+                let cert = challenge_certificate(domain, key_authorization.digest().as_ref())?;
+                RESOLVER
+                    .challenges
+                    .lock()
+                    .unwrap()
+                    .insert(domain.clone(), cert);
+            }
+        }
+        ready.push(challenge.url.clone());
+    }
+
+    let mut result = Ok(());
+    for url in &ready {
+        result = order
+            .set_challenge_ready(url)
+            .await
+            .map_err(|err| err.to_string());
+        if result.is_err() {
+            break;
+        }
+    }
+    if result.is_ok() {
+        result = poll(&mut order).await;
+    }
+    HTTP_CHALLENGES.lock().unwrap().clear();
+    RESOLVER.challenges.lock().unwrap().clear();
+    result?;
+
+    let mut params = CertificateParams::new(config.domains.clone());
+    params.distinguished_name = DistinguishedName::new();
+    let cert = rcgen::Certificate::from_params(params).map_err(|err| err.to_string())?;
+    let csr = cert
+        .serialize_request_der()
+        .map_err(|err| err.to_string())?;
+    order.finalize(&csr).await.map_err(|err| err.to_string())?;
+    let mut chain = None;
+    for _ in 0..10 {
+        chain = order.certificate().await.map_err(|err| err.to_string())?;
+        if chain.is_some() {
+            break;
+        }
+        tokio_time::sleep(Duration::from_secs(1)).await;
+    }
+    let chain = chain.ok_or("The certificate wasn't issued in time")?;
+
+    write_private(
+        &config.cache_dir.join(KEY_FILE),
+        &cert.serialize_private_key_pem(),
+    )
+    .and_then(|_| write_private(&config.cache_dir.join(CERT_FILE), &chain))
+    .map_err(|err| err.to_string())?;
+    install(config).map(|_| ())
+}
+
+// Seconds until the certificate should be renewed: `renew_days` before expiring, but no later
+// than after two thirds of its lifetime for short-lived certificates
+fn renew_in(config: &AcmeConfig, (not_before, not_after): (u64, u64)) -> u64 {
+    let lifetime = not_after.saturating_sub(not_before);
+    let before = (config.renew_days * 86400).min(lifetime / 3);
+
+    not_after.saturating_sub(before).saturating_sub(now())
+}
+
+// Serves the stored certificate right away, then keeps it renewed in the background
+pub fn init(config: &'static AcmeConfig) -> Result<(), String> {
+    fs::create_dir_all(&config.cache_dir).map_err(|err| {
+        format!(
+            "Unable to create the ACME directory '{}'! Reason: {}.",
+            config.cache_dir.display(),
+            err
+        )
+    })?;
+    let mut validity = install(config).ok();
+
+    tokio::spawn(async move {
+        loop {
+            if let Some(validity) = validity {
+                let renew_in = renew_in(config, validity);
+                if renew_in > 0 {
+                    tokio_time::sleep(Duration::from_secs(renew_in)).await;
+                }
+            }
+
+            match order(config).await {
+                Ok(()) => {
+                    validity = install(config).ok();
+                    println!(
+                        "Obtained a certificate for {} from the ACME server.",
+                        config.domains.join(", ")
+                    );
+                }
+                Err(err) => {
+                    eprintln!(
+                        "Unable to obtain a certificate from the ACME server! Reason: {}.",
+                        err
+                    );
+                    tokio_time::sleep(RETRY_AFTER).await;
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
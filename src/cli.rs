@@ -1,7 +1,17 @@
+#[cfg(unix)]
+use crate::systemd;
+use crate::{
+    client_certs,
+    config::{self, Config},
+    custom_headers,
+    hosts::{self, VirtualHost},
+    markdown,
+    mime_types::MimeTypes,
+};
 use clap::{
     builder,
     error::{Error as ClapError, ErrorKind},
-    Command as ClapCommand, Parser,
+    Command as ClapCommand, Parser, ValueEnum,
 };
 use std::{
     fmt::Display,
@@ -10,16 +20,64 @@ use std::{
     process,
 };
 
+#[derive(ValueEnum, Clone, Debug)]
+pub enum TrailingSlash {
+    /// Redirect `/about` to `/about/`.
+    Always,
+    /// Redirect `/about/` to `/about`.
+    Never,
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+pub enum Dotfiles {
+    /// Serve them like other files.
+    Allow,
+    /// Refuse them with '403 Forbidden'.
+    Deny,
+    /// Respond with '404 Not Found' as if they didn't exist.
+    Ignore,
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+pub enum Symlinks {
+    /// Follow every symlink, wherever it points to.
+    Follow,
+    /// Follow symlinks as long as their target stays inside of the served directory.
+    WithinRoot,
+    /// Refuse paths containing symlinks with '403 Forbidden'.
+    Deny,
+}
+
+#[derive(ValueEnum, Clone, Debug)]
+pub enum Overwrite {
+    /// Refuse the upload with '409 Conflict'.
+    Never,
+    /// Replace the existing file.
+    Always,
+    /// Store the upload under a numbered name like `report-1.txt`.
+    Rename,
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
     /// Path to the directory.
     pub path: PathBuf,
 
-    /// Address to bind, for example: 0.0.0.0:80.
+    /// Address to bind, for example: 0.0.0.0:80. Can be used multiple times, for example: -a 127.0.0.1:8085 -a [::1]:8085. Defaults to 127.0.0.1:8085 unless other listeners are configured.
     /// You may need administrator permissions for binding on port 80 based on your OS.
-    #[arg(short, long, default_value_t = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8085))]
-    pub addr: SocketAddr,
+    #[arg(short, long)]
+    pub addr: Vec<SocketAddr>,
+
+    /// Listen on a Unix domain socket, for example: --unix /run/sfhs.sock. A stale socket file is removed on start.
+    #[cfg(unix)]
+    #[arg(long, value_name = "PATH")]
+    pub unix: Option<PathBuf>,
+
+    /// Permissions of the Unix domain socket file in octal, for example: --unix-mode 660.
+    #[cfg(unix)]
+    #[arg(long, value_name = "MODE", value_parser = parse_mode, requires = "unix")]
+    pub unix_mode: Option<u32>,
 
     /// Controling CORS via the 'Access-Control-Allow-Origin' header.
     #[arg(short = 'r', long, default_value_t = String::from("*"))]
@@ -36,6 +94,211 @@ pub struct Args {
     /// Set cache time (in seconds) for cache-control max-age header, for eaxmple: -c10 for 10 seconds. Use -c-1 to disable caching.
     #[arg(short, long, default_value_t = 3600)]
     pub cache: i64,
+
+    /// Serve the index file for missing paths without a file extension, for single-page applications.
+    #[arg(long, default_value_t = false)]
+    pub spa: bool,
+
+    /// Serve another directory under a URL prefix, for example: --mount /docs=./docs/build. Can be used multiple times.
+    #[arg(short, long, value_name = "PREFIX=DIR")]
+    pub mount: Vec<String>,
+
+    /// Serve another directory for requests to a host name, for example: --vhost blog.example.com=./blog or --vhost *.preview.local=./preview. Can be used multiple times.
+    #[arg(long, value_name = "NAME=DIR")]
+    pub vhost: Vec<String>,
+
+    /// Serve `/about` from `about.html` or `about/index.html` and redirect `/about.html` to `/about`.
+    #[arg(long, default_value_t = false)]
+    pub clean_urls: bool,
+
+    /// Trailing slash policy of clean URLs. Directories keep their trailing slash by default.
+    #[arg(long, value_enum, requires = "clean_urls")]
+    pub trailing_slash: Option<TrailingSlash>,
+
+    /// Serve everything under a URL prefix, for example: --base-path /app when running behind a reverse proxy at https://example.com/app/.
+    #[arg(long)]
+    pub base_path: Option<String>,
+
+    /// What happens to requests for files and directories starting with a dot, except for `.well-known`. Secrets like `.env` or `.git` are never served.
+    #[arg(long, value_enum, default_value_t = Dotfiles::Ignore)]
+    pub dotfiles: Dotfiles,
+
+    /// Which symlinks inside of the served directories are followed. Use `within-root` or `deny` when others can create files in them.
+    #[arg(long, value_enum, default_value_t = Symlinks::Follow)]
+    pub symlinks: Symlinks,
+
+    /// Set `X-Content-Type-Options`, `Referrer-Policy`, `X-Frame-Options`, `Permissions-Policy` and a baseline `Content-Security-Policy` on every response. Can be overridden per path in the config file.
+    #[arg(long)]
+    pub secure_headers: bool,
+
+    /// Value of the `Server` header, which isn't sent by default.
+    #[arg(long, value_name = "VALUE")]
+    pub server_header: Option<String>,
+
+    #[arg(skip)]
+    pub header_rules: Vec<custom_headers::Rule>,
+
+    /// Path to a TOML config file, for example one containing Netlify style `[[redirects]]` rules.
+    #[arg(long = "config")]
+    pub config_file: Option<PathBuf>,
+
+    #[arg(skip)]
+    pub config: Config,
+
+    /// Path to a `mime.types` file of Apache or nginx, mapping extensions to MIME types. Extends and overrides the built-in types.
+    #[arg(long = "mime-types", value_name = "FILE")]
+    pub mime_types_file: Option<PathBuf>,
+
+    #[arg(skip)]
+    pub mime_types: MimeTypes,
+
+    /// Charset appended to the Content-Type of text files. Use --charset none to leave it out.
+    #[arg(long, default_value = "utf-8")]
+    pub charset: String,
+
+    /// Render `.md` files as HTML for browsers, with highlighted code blocks and heading anchors. Other clients and `?raw` requests receive the source. Directories without an index file show their README.md. Raw HTML in the files is rendered as it is, so only serve Markdown you trust.
+    #[arg(long, default_value_t = false)]
+    pub markdown: bool,
+
+    /// HTML template of rendered Markdown, in which `{{title}}` and `{{content}}` are replaced. A built-in page is used by default.
+    #[arg(long, value_name = "FILE", requires = "markdown")]
+    pub markdown_template: Option<PathBuf>,
+
+    #[arg(skip)]
+    pub markdown_page: Option<String>,
+
+    #[arg(skip)]
+    pub hosts: Vec<VirtualHost>,
+
+    /// Redirect plain HTTP requests on this address to HTTPS, for example: --redirect-http 0.0.0.0:80. Needs a TLS listener in the config file.
+    #[arg(long, value_name = "ADDR")]
+    pub redirect_http: Option<SocketAddr>,
+
+    /// Send `Strict-Transport-Security` with this max-age in seconds on HTTPS responses, for example: --hsts 31536000.
+    #[arg(long, value_name = "SECONDS")]
+    pub hsts: Option<u64>,
+
+    /// Apply HSTS to all subdomains as well.
+    #[arg(long, requires = "hsts")]
+    pub hsts_include_subdomains: bool,
+
+    /// Allow the domain to be added to the HSTS preload lists of browsers.
+    #[arg(long, requires = "hsts_include_subdomains")]
+    pub hsts_preload: bool,
+
+    /// Log every request to stdout in the Combined Log Format, with the subject of a verified client certificate as the user.
+    #[arg(long, default_value_t = false)]
+    pub access_log: bool,
+
+    /// Serve Prometheus metrics at `/metrics`.
+    #[arg(long, default_value_t = false)]
+    pub metrics: bool,
+
+    /// Serve the Prometheus metrics on a separate address instead, for example: --metrics-addr 127.0.0.1:9090.
+    #[arg(long, value_name = "ADDR", requires = "metrics")]
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// Serve liveness and readiness checks as JSON. They aren't rate limited or counted in the metrics.
+    #[arg(long, default_value_t = false)]
+    pub health_checks: bool,
+
+    /// Path of the liveness check.
+    #[arg(long, value_name = "PATH", default_value = "/healthz", value_parser = parse_endpoint)]
+    pub health_path: String,
+
+    /// Path of the readiness check, which fails while a served directory can't be read.
+    #[arg(long, value_name = "PATH", default_value = "/readyz", value_parser = parse_endpoint)]
+    pub ready_path: String,
+
+    /// Count the health checks in the metrics and apply the rate limits to them like other requests.
+    #[arg(long, default_value_t = false, requires = "health_checks")]
+    pub count_health_checks: bool,
+
+    /// Serve the directories read-only over WebDAV, so they can be mounted in file managers.
+    #[arg(long, default_value_t = false)]
+    pub webdav: bool,
+
+    /// Accept file uploads via `PUT` to a path and `multipart/form-data` `POST` to a directory. Needs --upload-auth.
+    #[arg(long, default_value_t = false, requires = "upload_auth")]
+    pub allow_upload: bool,
+
+    /// Accept `DELETE` requests for files as well.
+    #[arg(long, default_value_t = false, requires = "allow_upload")]
+    pub allow_delete: bool,
+
+    /// Credentials for uploads and deletions via basic authentication.
+    #[arg(long, value_name = "USER:PASSWORD", env = "SFHS_UPLOAD_AUTH", hide_env_values = true, value_parser = parse_credentials)]
+    pub upload_auth: Option<String>,
+
+    /// Maximum size of the files uploaded by a single request in bytes.
+    #[arg(long, value_name = "BYTES", default_value_t = 104857600)]
+    pub max_upload_size: u64,
+
+    /// What happens to uploads of existing files.
+    #[arg(long, value_enum, default_value_t = Overwrite::Never)]
+    pub upload_overwrite: Overwrite,
+
+    /// Maximum number of requests per second for each client IP. Exceeding clients receive '429 Too Many Requests'.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub rate_limit: Option<u32>,
+
+    /// Number of requests a client IP can send at once before the rate limit kicks in. Defaults to the rate limit.
+    #[arg(long, requires = "rate_limit", value_parser = clap::value_parser!(u32).range(1..))]
+    pub rate_limit_burst: Option<u32>,
+
+    /// Maximum number of response bytes per second for each client IP.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub bandwidth_limit: Option<u64>,
+
+    /// Maximum number of concurrent connections, further ones receive '429 Too Many Requests'. Use 0 to disable the limit.
+    #[arg(long, default_value_t = 10000)]
+    pub max_connections: usize,
+
+    /// Maximum number of concurrent connections for each client IP, further ones receive '429 Too Many Requests'.
+    #[arg(long)]
+    pub max_connections_per_ip: Option<usize>,
+
+    /// Seconds a client has to send the headers of a request. Use 0 to disable the timeout.
+    #[arg(long, value_name = "SECS", default_value_t = 10)]
+    pub header_read_timeout: u64,
+
+    /// Seconds a connection may go without reading or writing anything before it's closed. Use 0 to disable the timeout.
+    #[arg(long, value_name = "SECS", default_value_t = 60)]
+    pub idle_timeout: u64,
+
+    /// Seconds a client may stop receiving a response before the connection is closed. Use 0 to disable the timeout.
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    pub write_timeout: u64,
+
+    /// Maximum size of the request headers in bytes. Larger requests receive '431 Request Header Fields Too Large'.
+    #[arg(long, value_name = "BYTES", default_value_t = 32768, value_parser = clap::value_parser!(u32).range(8192..))]
+    pub max_header_size: u32,
+}
+
+#[cfg(unix)]
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    match u32::from_str_radix(mode, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("'{}' is not an octal file mode", mode)),
+    }
+}
+
+fn parse_credentials(credentials: &str) -> Result<String, String> {
+    match credentials.split_once(':') {
+        Some((user, _)) if !user.is_empty() => Ok(String::from(credentials)),
+        _ => Err(String::from("expected USER:PASSWORD")),
+    }
+}
+
+fn parse_endpoint(path: &str) -> Result<String, String> {
+    if path.len() < 2 || !path.starts_with('/') || path.ends_with('/') {
+        return Err(format!(
+            "'{}' must start with '/' and not end with '/'",
+            path
+        ));
+    }
+
+    Ok(String::from(path))
 }
 
 pub fn unrecoverable_clap_error(cmd: &builder::Command, message: impl Display) {
@@ -52,7 +315,7 @@ pub fn unrecoverable_clap_error_with_cmd(message: impl Display) {
 }
 
 pub fn parse() -> Args {
-    let args = Args::parse();
+    let mut args = Args::parse();
     let cmd = ClapCommand::new("static-file-http-server");
 
     let Args {
@@ -71,5 +334,80 @@ pub fn parse() -> Args {
         );
     }
 
+    if let Some(config_file) = &args.config_file {
+        match config::load(config_file) {
+            Ok(config) => args.config = config,
+            Err(err) => unrecoverable_clap_error(&cmd, err),
+        }
+    }
+    match MimeTypes::load(args.mime_types_file.as_deref(), &args.config.mime_types) {
+        Ok(mime_types) => args.mime_types = mime_types,
+        Err(err) => unrecoverable_clap_error(&cmd, err),
+    }
+    match custom_headers::load(&args) {
+        Ok(rules) => args.header_rules = rules,
+        Err(err) => unrecoverable_clap_error(&cmd, format!("{}!", err)),
+    }
+    if let Some(template) = &args.markdown_template {
+        match markdown::load_template(template) {
+            Ok(page) => args.markdown_page = Some(page),
+            Err(err) => unrecoverable_clap_error(&cmd, err),
+        }
+    }
+    if let Some(base_path) = &args.base_path {
+        if !base_path.starts_with('/') {
+            unrecoverable_clap_error(&cmd, "The base path must start with '/'!");
+        }
+
+        let base_path = base_path.trim_end_matches('/');
+        args.base_path = (!base_path.is_empty()).then(|| String::from(base_path));
+    }
+    if args.health_checks && args.health_path == args.ready_path {
+        unrecoverable_clap_error(
+            &cmd,
+            "The liveness and readiness checks need different paths!",
+        );
+    }
+    if args.redirect_http.is_some()
+        && !args
+            .config
+            .listeners
+            .iter()
+            .any(|listener| listener.tls_cert.is_some() || listener.acme)
+    {
+        unrecoverable_clap_error(
+            &cmd,
+            "Redirecting to HTTPS needs a TLS listener in the config file!",
+        );
+    }
+    if let Err(err) = client_certs::validate(&args) {
+        unrecoverable_clap_error(&cmd, format!("{}!", err));
+    }
+    if let Some(acme) = &args.config.acme {
+        if acme.domains.is_empty() {
+            unrecoverable_clap_error(&cmd, "ACME needs at least one domain!");
+        }
+        if !acme.accept_terms {
+            unrecoverable_clap_error(
+                &cmd,
+                "ACME needs 'accept_terms = true' to agree to the terms of service of the CA!",
+            );
+        }
+    }
+    #[cfg(unix)]
+    let unix = args.unix.is_some() || systemd::is_socket_activated();
+    #[cfg(not(unix))]
+    let unix = false;
+    if args.addr.is_empty() && args.config.listeners.is_empty() && !unix {
+        args.addr.push(SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            8085,
+        ));
+    }
+    match hosts::load(&args) {
+        Ok(hosts) => args.hosts = hosts,
+        Err(err) => unrecoverable_clap_error(&cmd, format!("{}!", err)),
+    }
+
     args
 }
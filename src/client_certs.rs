@@ -0,0 +1,66 @@
+use crate::{cli, limiter::ClientInfo, redirects, server};
+use axum::{
+    extract::ConnectInfo,
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+// Only '*' is special and matches any characters, so subjects with escaped commas can be copied
+// as they are
+fn matches(pattern: &[u8], subject: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => subject.is_empty(),
+        Some((b'*', rest)) => (0..=subject.len()).any(|i| matches(rest, &subject[i..])),
+        Some((c, rest)) => subject.first() == Some(c) && matches(rest, &subject[1..]),
+    }
+}
+
+pub fn validate(args: &cli::Args) -> Result<(), String> {
+    for rule in &args.config.client_certs {
+        if !rule.path.starts_with('/') {
+            return Err(format!(
+                "The client certificate path '{}' must start with '/'",
+                rule.path
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// Every rule matching the path has to be satisfied, clients without a verified certificate are
+// refused by all of them
+pub async fn middleware<B>(req: Request<B>, next: Next<B>, args: &'static cli::Args) -> Response {
+    let path = server::strip_base_path(req.uri().path(), args)
+        .and_then(server::relative_path)
+        .map(|path| format!("/{}", path))
+        .unwrap_or_else(|| String::from(req.uri().path()));
+    let subject = req
+        .extensions()
+        .get::<ConnectInfo<ClientInfo>>()
+        .and_then(|ConnectInfo(client)| client.subject.as_deref());
+
+    let allowed = args
+        .config
+        .client_certs
+        .iter()
+        .filter(|rule| redirects::match_path(&rule.path, &path).is_some())
+        .all(|rule| match subject {
+            Some(subject) => {
+                rule.subjects.is_empty()
+                    || rule
+                        .subjects
+                        .iter()
+                        .any(|pattern| matches(pattern.as_bytes(), subject.as_bytes()))
+            }
+            None => false,
+        });
+    if !allowed {
+        return server::error_response(StatusCode::FORBIDDEN, req.headers(), req.uri(), args)
+            .await
+            .into_response();
+    }
+
+    next.run(req).await
+}
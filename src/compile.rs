@@ -1,6 +1,12 @@
-use crate::cli;
+use crate::{access, cli, mime_types, mounts::Mount, redirects, server, ssi};
+use axum::http::Uri;
 use static_file_http_server_macros::{get_sbp_cargo_toml, get_sbp_main_rs};
-use std::process::Command;
+use std::{
+    fs as std_fs,
+    path::Path,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
 use tokio::{
     fs::{self, File as TokioFile},
     io::AsyncWriteExt,
@@ -8,6 +14,8 @@ use tokio::{
 
 get_sbp_main_rs!("SBP_MAIN_RS");
 get_sbp_cargo_toml!("SBP_CARGO_TOML");
+// Shared with the binary instead of copied into its template
+const SYSTEMD_RS: &str = include_str!("systemd.rs");
 
 async fn write_to_cache(path: &impl ToString, content: impl ToString) {
     let mut main_rs = TokioFile::create(format!(
@@ -23,23 +31,320 @@ async fn write_to_cache(path: &impl ToString, content: impl ToString) {
         .unwrap();
 }
 
+fn serialize_rules(rules: &[redirects::Rule]) -> String {
+    rules
+        .iter()
+        .map(|rule| rule.to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Copies the files of a mount that may be served into the cache, so the protected ones never end
+// up in the binary. Returns the absolute path of the copy.
+async fn stage_mount(mount: &Mount, i: usize, args: &cli::Args) -> String {
+    let dest = format!("./.static-file-http-server-cache/public/{}", i);
+    if let Err(err) = access::copy_allowed(mount, Path::new(&dest), args) {
+        cli::unrecoverable_clap_error_with_cmd(format!(
+            "Failed to copy '{}' to be embedded! Reason: {}.",
+            mount.path.display(),
+            err
+        ));
+    }
+
+    render_ssi(mount, Path::new(&dest), "", args);
+
+    let absolute_path = fs::canonicalize(&dest).await.unwrap();
+    String::from(absolute_path.to_str().unwrap())
+}
+
+// Server-side includes are processed when building, so the binary serves the rendered pages. The
+// dates and the query string of the request are the ones of the build.
+fn render_ssi(mount: &Mount, dir: &Path, prefix: &str, args: &cli::Args) {
+    let entries = match std_fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        let path = format!("{}{}", prefix, name);
+        if entry.path().is_dir() {
+            render_ssi(mount, &entry.path(), &format!("{}/", path), args);
+            continue;
+        }
+        if !ssi::is_ssi(&path) {
+            continue;
+        }
+
+        let uri = format!("{}/{}", mount.prefix, server::encode_path(&path))
+            .parse::<Uri>()
+            .unwrap_or_default();
+        if let Ok(contents) = std_fs::read(entry.path()) {
+            let _ = std_fs::write(
+                entry.path(),
+                ssi::render(&contents, &path, mount, &uri, args),
+            );
+        }
+    }
+}
+
+// The Content-Type of every file in a staged directory, so the binary uses the same MIME types as
+// the server
+fn content_types(dir: &Path, args: &cli::Args) -> String {
+    fn collect(dir: &Path, prefix: &str, args: &cli::Args, types: &mut Vec<(String, String)>) {
+        let entries = match std_fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.flatten() {
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            let path = format!("{}{}", prefix, name);
+            if entry.path().is_dir() {
+                collect(&entry.path(), &format!("{}/", path), args, types);
+            } else {
+                types.push((mime_types::of_file(&entry.path(), args), path));
+            }
+        }
+    }
+
+    let mut types = vec![];
+    collect(dir, "", args, &mut types);
+    types.sort_by(|a, b| a.1.cmp(&b.1));
+
+    let types = types
+        .iter()
+        .map(|(mime, path)| format!("({:?}, {:?})", path, mime))
+        .collect::<Vec<_>>();
+    format!("&[{}]", types.join(", "))
+}
+
 async fn create_the_sample_project(args: &cli::Args) {
     write_to_cache(&"Cargo.toml", SBP_CARGO_TOML).await;
 
     let main_rs_path = "src/main.rs";
     let mut main_rs = SBP_MAIN_RS.to_string();
-    main_rs = main_rs.replace("127.0.0.1:8080", args.addr.to_string().as_str());
+    // TLS listeners aren't embedded, the single binary only serves plain HTTP
+    let addrs = args
+        .addr
+        .iter()
+        .chain(
+            args.config
+                .listeners
+                .iter()
+                .filter(|listener| listener.tls_cert.is_none() && !listener.acme)
+                .map(|listener| &listener.addr),
+        )
+        .map(|addr| format!("{:?}", addr.to_string()))
+        .collect::<Vec<_>>();
+    main_rs = main_rs.replace(
+        "&[\"127.0.0.1:8080\"]",
+        format!("&[{}]", addrs.join(", ")).as_str(),
+    );
     main_rs = main_rs.replace("3600", args.cache.to_string().as_str());
 
-    let absolute_path = fs::canonicalize(&args.path).await.unwrap();
-    main_rs = main_rs.replace("./_public_dir_", absolute_path.to_str().unwrap());
+    let default_host = &args.hosts[0];
+    let mut staged = 0;
+    let root = stage_mount(&default_host.mounts[0], staged, args).await;
+    main_rs = main_rs.replace("./_public_dir_", &root);
+    main_rs = main_rs.replace(
+        "static ROOT_CONTENT_TYPES: &[(&str, &str)] = &[];",
+        format!(
+            "static ROOT_CONTENT_TYPES: &[(&str, &str)] = {};",
+            content_types(Path::new(&root), args)
+        )
+        .as_str(),
+    );
 
     // Because two '*' characters are used in the file
     main_rs = main_rs.replace(
-        "Lazy::new(|| String::from(\"*\"))",
-        format!("Lazy::new(|| String::from(\"{}\"))", args.cors).as_str(),
+        "const CORS: &str = \"*\";",
+        format!("const CORS: &str = {:?};", args.cors).as_str(),
+    );
+    main_rs = main_rs.replace(
+        "const SPA: bool = false;",
+        format!("const SPA: bool = {};", args.spa).as_str(),
+    );
+
+    let mut mounts = vec![];
+    for mount in &default_host.mounts[1..] {
+        staged += 1;
+        let absolute_path = stage_mount(mount, staged, args).await;
+        mounts.push(format!(
+            "Mount {{ prefix: {:?}, dir: include_directory!({:?}), index: Some({:?}), spa: {}, cache: {}, cors: {:?}, content_types: {} }}",
+            mount.prefix,
+            absolute_path,
+            mount.index,
+            mount.spa,
+            mount.cache,
+            mount.cors,
+            content_types(Path::new(&absolute_path), args)
+        ));
+    }
+    main_rs = main_rs.replace(
+        "static MOUNTS: &[Mount] = &[];",
+        format!("static MOUNTS: &[Mount] = &[{}];", mounts.join(", ")).as_str(),
+    );
+
+    main_rs = main_rs.replace(
+        "\"_redirects_\"",
+        format!("{:?}", serialize_rules(&default_host.rules)).as_str(),
+    );
+
+    let mut hosts = vec![];
+    for host in &args.hosts[1..] {
+        let root = &host.mounts[0];
+        staged += 1;
+        let absolute_path = stage_mount(root, staged, args).await;
+        hosts.push(format!(
+            "Host {{ names: &{:?}, root: Mount {{ prefix: \"\", dir: include_directory!({:?}), index: Some({:?}), spa: {}, cache: {}, cors: {:?}, content_types: {} }}, redirects: {:?} }}",
+            host.names,
+            absolute_path,
+            root.index,
+            root.spa,
+            root.cache,
+            root.cors,
+            content_types(Path::new(&absolute_path), args),
+            serialize_rules(&host.rules)
+        ));
+    }
+    let header_rules = args
+        .header_rules
+        .iter()
+        .map(|rule| {
+            let values = rule
+                .values
+                .iter()
+                .map(|(name, value)| {
+                    let value = value
+                        .as_ref()
+                        .map(|value| String::from_utf8_lossy(value.as_bytes()).to_string())
+                        .unwrap_or_default();
+                    format!("({:?}, {:?})", name.as_str(), value)
+                })
+                .collect::<Vec<_>>();
+            format!("({:?}, &[{}])", rule.path, values.join(", "))
+        })
+        .collect::<Vec<_>>();
+    main_rs = main_rs.replace(
+        "static HEADER_RULES: &[(&str, &[(&str, &str)])] = &[];",
+        format!(
+            "static HEADER_RULES: &[(&str, &[(&str, &str)])] = &[{}];",
+            header_rules.join(", ")
+        )
+        .as_str(),
     );
+    main_rs = main_rs.replace(
+        "static HOSTS: &[Host] = &[];",
+        format!("static HOSTS: &[Host] = &[{}];", hosts.join(", ")).as_str(),
+    );
+    #[cfg(unix)]
+    if let Some(path) = &args.unix {
+        main_rs = main_rs.replace(
+            "const UNIX_SOCKET: Option<&str> = None;",
+            format!(
+                "const UNIX_SOCKET: Option<&str> = Some({:?});",
+                path.to_str().unwrap()
+            )
+            .as_str(),
+        );
+    }
+    #[cfg(unix)]
+    if let Some(mode) = args.unix_mode {
+        main_rs = main_rs.replace(
+            "const UNIX_MODE: Option<u32> = None;",
+            format!("const UNIX_MODE: Option<u32> = Some(0o{:o});", mode).as_str(),
+        );
+    }
+    main_rs = main_rs.replace(
+        "const CLEAN_URLS: bool = false;",
+        format!("const CLEAN_URLS: bool = {};", args.clean_urls).as_str(),
+    );
+    if let Some(base_path) = &args.base_path {
+        main_rs = main_rs.replace(
+            "const BASE_PATH: &str = \"\";",
+            format!("const BASE_PATH: &str = {:?};", base_path).as_str(),
+        );
+    }
+    if let Some(trailing_slash) = &args.trailing_slash {
+        main_rs = main_rs.replace(
+            "const TRAILING_SLASH: Option<TrailingSlash> = None;",
+            format!(
+                "const TRAILING_SLASH: Option<TrailingSlash> = Some(TrailingSlash::{:?});",
+                trailing_slash
+            )
+            .as_str(),
+        );
+    }
+    if args.health_checks {
+        main_rs = main_rs.replace(
+            "const HEALTH_CHECKS: bool = false;",
+            "const HEALTH_CHECKS: bool = true;",
+        );
+        main_rs = main_rs.replace(
+            "const HEALTH_PATH: &str = \"/healthz\";",
+            format!("const HEALTH_PATH: &str = {:?};", args.health_path).as_str(),
+        );
+        main_rs = main_rs.replace(
+            "const READY_PATH: &str = \"/readyz\";",
+            format!("const READY_PATH: &str = {:?};", args.ready_path).as_str(),
+        );
+    }
+    main_rs = main_rs.replace(
+        "const HEADER_READ_TIMEOUT: u64 = 10;",
+        format!(
+            "const HEADER_READ_TIMEOUT: u64 = {};",
+            args.header_read_timeout
+        )
+        .as_str(),
+    );
+    main_rs = main_rs.replace(
+        "const IDLE_TIMEOUT: u64 = 60;",
+        format!("const IDLE_TIMEOUT: u64 = {};", args.idle_timeout).as_str(),
+    );
+    main_rs = main_rs.replace(
+        "const WRITE_TIMEOUT: u64 = 30;",
+        format!("const WRITE_TIMEOUT: u64 = {};", args.write_timeout).as_str(),
+    );
+    main_rs = main_rs.replace(
+        "const MAX_HEADER_SIZE: usize = 32768;",
+        format!("const MAX_HEADER_SIZE: usize = {};", args.max_header_size).as_str(),
+    );
+    main_rs = main_rs.replace(
+        "const ERROR_PAGE_CONTENT_TYPE: &str = \"text/html; charset=utf-8\";",
+        format!(
+            "const ERROR_PAGE_CONTENT_TYPE: &str = {:?};",
+            mime_types::with_charset(String::from("text/html"), args)
+        )
+        .as_str(),
+    );
+    main_rs = main_rs.replace(
+        "const MAX_CONNECTIONS: usize = 10000;",
+        format!("const MAX_CONNECTIONS: usize = {};", args.max_connections).as_str(),
+    );
+    main_rs = main_rs.replace(
+        "const BUILD_VERSION: &str = \"0.0.0\";",
+        format!(
+            "const BUILD_VERSION: &str = {:?};",
+            env!("CARGO_PKG_VERSION")
+        )
+        .as_str(),
+    );
+    let built_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+    main_rs = main_rs.replace(
+        "const BUILT_AT: u64 = 0;",
+        format!("const BUILT_AT: u64 = {};", built_at).as_str(),
+    );
+    main_rs = main_rs.replace("#[path = \"../../src/systemd.rs\"]\n", "");
     write_to_cache(&main_rs_path, main_rs).await;
+    write_to_cache(&"src/systemd.rs", SYSTEMD_RS).await;
 }
 
 fn command_exists_or_exit() {
@@ -75,6 +380,12 @@ async fn run_cargo(current_dir: impl ToString) {
 }
 
 pub async fn compile(args: &cli::Args) {
+    // The single binary only serves plain HTTP, the protected paths would be open to everyone
+    if !args.config.client_certs.is_empty() {
+        cli::unrecoverable_clap_error_with_cmd(
+            "The single binary can't verify client certificates for the [[client_certs]] rules!",
+        );
+    }
     if let Err(err) = fs::create_dir_all("./.static-file-http-server-cache/src").await {
         cli::unrecoverable_clap_error_with_cmd(format!(
             "Failed to create the cache directory! Reason: {}.",
@@ -0,0 +1,175 @@
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+fn default_redirect_status() -> u16 {
+    301
+}
+
+// Mirrors the `[[redirects]]` tables of netlify.toml
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RedirectConfig {
+    pub from: String,
+    pub to: String,
+    #[serde(default = "default_redirect_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub force: bool,
+    #[serde(default)]
+    pub query: BTreeMap<String, String>,
+}
+
+// Mirrors the `[[headers]]` tables of netlify.toml. Empty values remove the header.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct HeadersConfig {
+    #[serde(rename = "for")]
+    pub path: String,
+    pub values: BTreeMap<String, String>,
+}
+
+// Paths only served to clients presenting a verified certificate whose subject matches one of
+// the patterns, or any verified certificate without patterns
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ClientCertsConfig {
+    #[serde(rename = "for")]
+    pub path: String,
+    #[serde(default)]
+    pub subjects: Vec<String>,
+}
+
+// Unset settings of a mount fall back to the command-line options
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct MountConfig {
+    pub prefix: String,
+    pub path: PathBuf,
+    pub index: Option<String>,
+    pub spa: Option<bool>,
+    pub cache: Option<i64>,
+    pub cors: Option<String>,
+}
+
+// Unset settings of a virtual host fall back to the command-line options
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct HostConfig {
+    pub names: Vec<String>,
+    pub path: PathBuf,
+    pub index: Option<String>,
+    pub spa: Option<bool>,
+    pub cache: Option<i64>,
+    pub cors: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    // HTTP/1.1 and HTTP/2, negotiated with ALPN over TLS
+    #[default]
+    Auto,
+    Http1,
+    Http2,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuth {
+    // Handshakes without a certificate signed by the CA fail
+    #[default]
+    Required,
+    // Clients without a certificate are served as well, but a presented one must be valid
+    Optional,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub addr: SocketAddr,
+    #[serde(default)]
+    pub protocol: Protocol,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    // Serves the certificate obtained via `[acme]` instead
+    #[serde(default)]
+    pub acme: bool,
+    // Serves HTTP/3 over QUIC on the same port as well, which needs TLS
+    #[serde(default)]
+    pub http3: bool,
+    // The CA bundle client certificates are verified against
+    pub client_ca: Option<PathBuf>,
+    #[serde(default)]
+    pub client_auth: ClientAuth,
+}
+
+fn default_acme_directory() -> String {
+    String::from("https://acme-v02.api.letsencrypt.org/directory")
+}
+
+fn default_acme_cache_dir() -> PathBuf {
+    PathBuf::from("./.sfhs-acme")
+}
+
+fn default_acme_renew_days() -> u64 {
+    30
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub enum AcmeChallenge {
+    // Answered by the plain HTTP listeners on port 80
+    #[default]
+    #[serde(rename = "http-01")]
+    Http01,
+    // Answered by the ACME listeners on port 443
+    #[serde(rename = "tls-alpn-01")]
+    TlsAlpn01,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AcmeConfig {
+    pub domains: Vec<String>,
+    #[serde(default)]
+    pub contact: Vec<String>,
+    // Let's Encrypt by default, any RFC 8555 directory like a local Pebble works as well
+    #[serde(default = "default_acme_directory")]
+    pub directory: String,
+    #[serde(default)]
+    pub challenge: AcmeChallenge,
+    // Where the account, the certificate and its key are stored
+    #[serde(default = "default_acme_cache_dir")]
+    pub cache_dir: PathBuf,
+    // How many days before expiring the certificate gets renewed
+    #[serde(default = "default_acme_renew_days")]
+    pub renew_days: u64,
+    #[serde(default)]
+    pub accept_terms: bool,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listeners: Vec<ListenerConfig>,
+    pub redirects: Vec<RedirectConfig>,
+    pub headers: Vec<HeadersConfig>,
+    pub client_certs: Vec<ClientCertsConfig>,
+    pub mounts: Vec<MountConfig>,
+    pub hosts: Vec<HostConfig>,
+    pub acme: Option<AcmeConfig>,
+    // Extensions mapped to MIME types, like `wasm = "application/wasm"`
+    pub mime_types: BTreeMap<String, String>,
+}
+
+pub fn load(path: &Path) -> Result<Config, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|err| format!("Failed to read the config file! Reason: {}.", err))?;
+
+    toml::from_str(&contents)
+        .map_err(|err| format!("Failed to parse the config file! Reason: {}.", err))
+}
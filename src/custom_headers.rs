@@ -0,0 +1,114 @@
+use crate::{cli, redirects, server};
+use axum::{
+    http::{
+        header::{HeaderName, HeaderValue},
+        Request,
+    },
+    middleware::Next,
+    response::Response,
+};
+use std::str::FromStr;
+
+// Set on every response by `--secure-headers`. The policy allows inline styles since they're
+// common in static sites, but neither inline scripts nor other origins.
+const SECURE_HEADERS: [(&str, &str); 5] = [
+    ("X-Content-Type-Options", "nosniff"),
+    ("Referrer-Policy", "strict-origin-when-cross-origin"),
+    ("X-Frame-Options", "SAMEORIGIN"),
+    (
+        "Permissions-Policy",
+        "camera=(), microphone=(), geolocation=(), payment=(), usb=()",
+    ),
+    (
+        "Content-Security-Policy",
+        "default-src 'self'; img-src 'self' data:; style-src 'self' 'unsafe-inline'; object-src 'none'; base-uri 'self'; form-action 'self'; frame-ancestors 'self'",
+    ),
+];
+
+// Headers set on the responses to paths matching the pattern, which uses the syntax of the
+// redirect rules. Later rules win over earlier ones.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub path: String,
+    // Headers without a value are removed
+    pub values: Vec<(HeaderName, Option<HeaderValue>)>,
+}
+
+impl Rule {
+    fn new<'a>(
+        path: &str,
+        values: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Result<Self, String> {
+        if !path.starts_with('/') {
+            return Err(format!("The header path '{}' must start with '/'", path));
+        }
+
+        let mut parsed = vec![];
+        for (name, value) in values {
+            let invalid = || format!("Invalid header '{}: {}' for '{}'", name, value, path);
+            let name = HeaderName::from_str(name).map_err(|_| invalid())?;
+            let value = match value {
+                "" => None,
+                value => Some(HeaderValue::from_str(value).map_err(|_| invalid())?),
+            };
+            parsed.push((name, value));
+        }
+
+        Ok(Self {
+            path: String::from(path),
+            values: parsed,
+        })
+    }
+}
+
+// The preset and the `Server` header apply to every path, followed by the rules of the config
+// file
+pub fn load(args: &cli::Args) -> Result<Vec<Rule>, String> {
+    let mut defaults = vec![];
+    if args.secure_headers {
+        defaults.extend(SECURE_HEADERS);
+    }
+    if let Some(server) = &args.server_header {
+        defaults.push(("Server", server.as_str()));
+    }
+
+    let mut rules = vec![];
+    if !defaults.is_empty() {
+        rules.push(Rule::new("/*", defaults)?);
+    }
+    for config in &args.config.headers {
+        rules.push(Rule::new(
+            &config.path,
+            config
+                .values
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+        )?);
+    }
+
+    Ok(rules)
+}
+
+pub async fn middleware<B>(req: Request<B>, next: Next<B>, args: &'static cli::Args) -> Response {
+    // Paths outside of the base path, like the health checks, are matched as they are
+    let path = server::strip_base_path(req.uri().path(), args)
+        .and_then(server::relative_path)
+        .map(|path| format!("/{}", path))
+        .unwrap_or_else(|| String::from(req.uri().path()));
+
+    let mut res = next.run(req).await;
+    for rule in &args.header_rules {
+        if redirects::match_path(&rule.path, &path).is_none() {
+            continue;
+        }
+
+        for (name, value) in &rule.values {
+            match value {
+                Some(value) => res.headers_mut().insert(name.clone(), value.clone()),
+                None => res.headers_mut().remove(name),
+            };
+        }
+    }
+
+    res
+}
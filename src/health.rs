@@ -0,0 +1,93 @@
+use crate::{cli, server::SetHeader};
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use std::fmt::Write;
+use tokio::fs;
+
+fn json_string(value: &str) -> String {
+    let mut out = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+
+    out
+}
+
+fn json_response(status: StatusCode, body: String) -> Response {
+    (
+        status,
+        SetHeader(
+            header::CONTENT_TYPE.to_string(),
+            String::from("application/json"),
+        ),
+        SetHeader(header::CACHE_CONTROL.to_string(), String::from("no-store")),
+        body,
+    )
+        .into_response()
+}
+
+// Alive as long as the server answers
+pub async fn healthz() -> Response {
+    json_response(
+        StatusCode::OK,
+        format!(
+            "{{\"status\":\"ok\",\"version\":{}}}",
+            json_string(env!("CARGO_PKG_VERSION"))
+        ),
+    )
+}
+
+// Ready when the served directory and every mounted directory can be read
+pub async fn readyz(args: &cli::Args) -> Response {
+    let root_readable = fs::read_dir(&args.path).await.is_ok();
+    let mut ready = root_readable;
+    let mut mounts = vec![];
+    // Mounts are told apart by their host and prefix, the paths on disk aren't anyone's business
+    for host in &args.hosts {
+        let name = host
+            .names
+            .first()
+            .map_or_else(|| String::from("null"), |name| json_string(name));
+        for mount in &host.mounts {
+            let readable = fs::read_dir(&mount.path).await.is_ok();
+            ready &= readable;
+            let prefix = if mount.prefix.is_empty() {
+                "/"
+            } else {
+                &mount.prefix
+            };
+            mounts.push(format!(
+                "{{\"host\":{},\"prefix\":{},\"readable\":{}}}",
+                name,
+                json_string(prefix),
+                readable
+            ));
+        }
+    }
+
+    let (status, state) = if ready {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "unavailable")
+    };
+    json_response(
+        status,
+        format!(
+            "{{\"status\":{},\"root_readable\":{},\"mounts\":[{}],\"version\":{}}}",
+            json_string(state),
+            root_readable,
+            mounts.join(","),
+            json_string(env!("CARGO_PKG_VERSION"))
+        ),
+    )
+}
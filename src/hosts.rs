@@ -0,0 +1,161 @@
+use crate::{
+    access::Ignore,
+    cli,
+    mounts::{self, Mount},
+    redirects::{self, Rule},
+    server,
+};
+use axum::http::{header, HeaderMap, Uri};
+use std::path::PathBuf;
+
+// A site chosen by the `Host` header. The default host has no names and serves unmatched requests.
+#[derive(Debug)]
+pub struct VirtualHost {
+    // Exact names like `example.com` or wildcards like `*.preview.local`
+    pub names: Vec<String>,
+    pub mounts: Vec<Mount>,
+    pub rules: Vec<Rule>,
+}
+
+fn normalize_name(name: &str) -> Result<String, String> {
+    let name = name.trim().trim_end_matches('.').to_ascii_lowercase();
+    let domain = name.strip_prefix("*.").unwrap_or(&name);
+    if domain.is_empty() || domain.contains(['*', '/', ':']) {
+        return Err(format!("Invalid virtual host name '{}'", name));
+    }
+
+    Ok(name)
+}
+
+fn new_host(
+    names: Vec<String>,
+    path: PathBuf,
+    index: Option<String>,
+    spa: bool,
+    cache: i64,
+    cors: String,
+) -> Result<VirtualHost, String> {
+    if !path.is_dir() {
+        return Err(format!(
+            "The virtual host path '{}' is not a directory",
+            path.display()
+        ));
+    }
+
+    let names = names
+        .iter()
+        .map(|name| normalize_name(name))
+        .collect::<Result<Vec<_>, _>>()?;
+    let rules = redirects::load(&path, &[])?;
+    let index = index.unwrap_or_else(|| server::root_serve(path.to_str().unwrap()));
+
+    Ok(VirtualHost {
+        names,
+        mounts: vec![Mount {
+            prefix: String::new(),
+            ignore: Ignore::load(&path)?,
+            path,
+            index,
+            spa,
+            cache,
+            cors,
+        }],
+        rules,
+    })
+}
+
+// The default host comes first, followed by the `--vhost` options and the `[[hosts]]` of the
+// config file
+pub fn load(args: &cli::Args) -> Result<Vec<VirtualHost>, String> {
+    let mut hosts = vec![VirtualHost {
+        names: vec![],
+        mounts: mounts::load(args)?,
+        rules: redirects::load(&args.path, &args.config.redirects)?,
+    }];
+
+    for vhost in &args.vhost {
+        let (name, path) = match vhost.split_once('=') {
+            Some(vhost) => vhost,
+            None => {
+                return Err(format!(
+                    "Invalid virtual host '{}', expected a NAME=DIRECTORY pair",
+                    vhost
+                ))
+            }
+        };
+
+        hosts.push(new_host(
+            vec![String::from(name)],
+            PathBuf::from(path),
+            None,
+            args.spa,
+            args.cache,
+            args.cors.clone(),
+        )?);
+    }
+
+    for host in &args.config.hosts {
+        hosts.push(new_host(
+            host.names.clone(),
+            host.path.clone(),
+            host.index.clone(),
+            host.spa.unwrap_or(args.spa),
+            host.cache.unwrap_or(args.cache),
+            host.cors.clone().unwrap_or_else(|| args.cors.clone()),
+        )?);
+    }
+
+    let mut names: Vec<&String> = hosts.iter().flat_map(|host| &host.names).collect();
+    names.sort();
+    if let Some(name) = names.windows(2).find(|names| names[0] == names[1]) {
+        return Err(format!(
+            "The virtual host '{}' is defined more than once",
+            name[0]
+        ));
+    }
+
+    Ok(hosts)
+}
+
+// Reads the requested host name from the `Host` header, or the URI authority for HTTP/2
+pub fn host_name(headers: &HeaderMap, uri: &Uri) -> Option<String> {
+    let host = match headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+    {
+        Some(host) => host,
+        None => uri.host()?,
+    };
+    let name = match host.strip_prefix('[') {
+        Some(ipv6) => ipv6.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    };
+
+    Some(name.trim_end_matches('.').to_ascii_lowercase())
+}
+
+// Exact names win over wildcards, and longer wildcards win over shorter ones
+pub fn find<'a>(hosts: &'a [VirtualHost], name: Option<&str>) -> &'a VirtualHost {
+    let name = match name {
+        Some(name) => name,
+        None => return &hosts[0],
+    };
+    if let Some(host) = hosts
+        .iter()
+        .find(|host| host.names.iter().any(|host_name| host_name == name))
+    {
+        return host;
+    }
+
+    hosts
+        .iter()
+        .flat_map(|host| host.names.iter().map(move |host_name| (host, host_name)))
+        .filter_map(|(host, host_name)| {
+            let domain = host_name.strip_prefix("*.")?;
+            let subdomain = name.strip_suffix(domain)?;
+            (subdomain.len() > 1 && subdomain.ends_with('.')).then_some((host, domain.len()))
+        })
+        .max_by_key(|(_, len)| *len)
+        .map(|(host, _)| host)
+        .unwrap_or(&hosts[0])
+}
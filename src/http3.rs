@@ -0,0 +1,149 @@
+use crate::{
+    cli,
+    limiter::{self, ClientInfo},
+    listener, metrics, x509,
+};
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::ConnectInfo,
+    http::{header, HeaderValue, Request, Response as HttpResponse},
+    middleware::Next,
+    response::Response,
+    Router,
+};
+use h3::server::RequestStream;
+use hyper::{body::Buf, service::Service};
+use quinn::{Endpoint, EndpointConfig, IdleTimeout, TokioRuntime, TransportConfig};
+use std::{future, net::SocketAddr, sync::Arc};
+use tokio_rustls::rustls::{Certificate, ServerConfig};
+
+type Stream = RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>;
+
+// The QUIC config of a listener, reusing the certificate and the client authentication of its TLS
+// config
+pub fn server_config(mut tls_config: ServerConfig, args: &cli::Args) -> quinn::ServerConfig {
+    tls_config.alpn_protocols = vec![b"h3".to_vec()];
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(tls_config));
+    let mut transport = TransportConfig::default();
+    transport.max_idle_timeout(
+        listener::timeout(args.idle_timeout)
+            .and_then(|timeout| IdleTimeout::try_from(timeout).ok()),
+    );
+    config.transport_config(Arc::new(transport));
+
+    config
+}
+
+pub fn bind(
+    addr: SocketAddr,
+    config: quinn::ServerConfig,
+    listeners: &[listener::Listener],
+) -> Result<Endpoint, String> {
+    let socket = listener::bind_udp(addr, listeners)?;
+
+    Endpoint::new(
+        EndpointConfig::default(),
+        Some(config),
+        socket,
+        TokioRuntime,
+    )
+    .map_err(|err| format!("Unable to bind on: {}/udp! Reason: {}.", addr, err))
+}
+
+// The `Alt-Svc` value advertising HTTP/3 on a port for a day
+pub fn alt_svc(port: u16) -> HeaderValue {
+    HeaderValue::from_str(&format!("h3=\":{}\"; ma=86400", port)).unwrap()
+}
+
+// Added to the HTTP/1.1 and HTTP/2 responses of listeners serving HTTP/3 as well
+pub async fn set_alt_svc<B>(req: Request<B>, next: Next<B>, alt_svc: HeaderValue) -> Response {
+    let mut res = next.run(req).await;
+    res.headers_mut().entry(header::ALT_SVC).or_insert(alt_svc);
+
+    res
+}
+
+// Streams the request body to the app and its response back to the client
+async fn handle(
+    req: Request<()>,
+    stream: Stream,
+    mut app: Router,
+    client: ClientInfo,
+) -> Result<(), h3::Error> {
+    let (mut send, mut recv) = stream.split();
+    let (mut body_sender, body) = Body::channel();
+    tokio::spawn(async move {
+        loop {
+            match recv.recv_data().await {
+                Ok(Some(mut chunk)) => {
+                    let chunk = chunk.copy_to_bytes(chunk.remaining());
+                    if body_sender.send_data(chunk).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                Err(_) => {
+                    body_sender.abort();
+                    break;
+                }
+            }
+        }
+    });
+
+    let (mut parts, ()) = req.into_parts();
+    parts.extensions.insert(ConnectInfo(client));
+    let _ = future::poll_fn(|cx| app.poll_ready(cx)).await;
+    let res = match app.call(Request::from_parts(parts, body)).await {
+        Ok(res) => res,
+        Err(err) => match err {},
+    };
+
+    let (parts, mut body) = res.into_parts();
+    send.send_response(HttpResponse::from_parts(parts, ()))
+        .await?;
+    while let Some(chunk) = body.data().await {
+        match chunk {
+            Ok(chunk) => send.send_data(chunk).await?,
+            // The client notices the missing end of the stream
+            Err(_) => return Ok(()),
+        }
+    }
+
+    send.finish().await
+}
+
+pub async fn serve(endpoint: Endpoint, app: Router, args: &'static cli::Args) {
+    while let Some(connecting) = endpoint.accept().await {
+        // Dropping connections over the limits refuses them before the handshake
+        let slot = match limiter::acquire(Some(connecting.remote_address())) {
+            Some(slot) => slot,
+            None => continue,
+        };
+        let mut client = ClientInfo::new(Some(connecting.remote_address()));
+        let app = app.clone();
+        tokio::spawn(async move {
+            let _slot = slot;
+            let connection = match connecting.await {
+                Ok(connection) => connection,
+                Err(_) => return,
+            };
+            let _connection = metrics::ConnectionGuard::new();
+            // Only certificates verified against the client CA are presented
+            client.subject = connection
+                .peer_identity()
+                .and_then(|identity| identity.downcast::<Vec<Certificate>>().ok())
+                .and_then(|certs| certs.first().and_then(|cert| x509::subject(&cert.0)));
+
+            let mut builder = h3::server::builder();
+            builder.max_field_section_size(args.max_header_size.into());
+            let mut connection = match builder.build(h3_quinn::Connection::new(connection)).await {
+                Ok(connection) => connection,
+                Err(_) => return,
+            };
+            // Errors of a single request, like clients going away, don't concern the server
+            while let Ok(Some((req, stream))) = connection.accept().await {
+                tokio::spawn(handle(req, stream, app.clone(), client.clone()));
+            }
+        });
+    }
+}
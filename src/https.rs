@@ -0,0 +1,89 @@
+use crate::{
+    cli, hosts,
+    server::{self, SetHeader},
+};
+use axum::{
+    http::{header, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+// Served over plain HTTP, so certificates can be issued before HTTPS works
+pub const ACME_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+// The `Strict-Transport-Security` value of `--hsts`
+pub fn hsts(args: &cli::Args) -> Option<HeaderValue> {
+    let mut value = format!("max-age={}", args.hsts?);
+    if args.hsts_include_subdomains {
+        value.push_str("; includeSubDomains");
+    }
+    if args.hsts_preload {
+        value.push_str("; preload");
+    }
+
+    HeaderValue::from_str(&value).ok()
+}
+
+// Added to the responses of TLS listeners only, browsers ignore it over plain HTTP
+pub async fn set_hsts<B>(req: Request<B>, next: Next<B>, hsts: HeaderValue) -> Response {
+    let mut res = next.run(req).await;
+    res.headers_mut()
+        .entry(header::STRICT_TRANSPORT_SECURITY)
+        .or_insert(hsts);
+
+    res
+}
+
+// Host names are put into the location as they are, so anything else than a name or an address
+// is refused
+fn is_valid_host(host: &str) -> bool {
+    !host.is_empty()
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | ':'))
+}
+
+// Redirects every request of the plain HTTP listener to the same path on the HTTPS port, except
+// for ACME challenges
+pub async fn redirect<B>(
+    req: Request<B>,
+    next: Next<B>,
+    https_port: u16,
+    args: &'static cli::Args,
+) -> Response {
+    if req.uri().path().starts_with(ACME_CHALLENGE_PREFIX) {
+        return next.run(req).await;
+    }
+
+    let host = match hosts::host_name(req.headers(), req.uri()) {
+        Some(host) if is_valid_host(&host) => host,
+        _ => {
+            return server::error_response(StatusCode::BAD_REQUEST, req.headers(), req.uri(), args)
+                .await
+                .into_response()
+        }
+    };
+    // IPv6 addresses are bracketed again
+    let host = if host.contains(':') {
+        format!("[{}]", host)
+    } else {
+        host
+    };
+    let port = match https_port {
+        443 => String::new(),
+        port => format!(":{}", port),
+    };
+    let path = req
+        .uri()
+        .path_and_query()
+        .map_or("/", |path_and_query| path_and_query.as_str());
+
+    (
+        StatusCode::MOVED_PERMANENTLY,
+        SetHeader(
+            header::LOCATION.to_string(),
+            format!("https://{}{}{}", host, port, path),
+        ),
+    )
+        .into_response()
+}
@@ -0,0 +1,357 @@
+use crate::{
+    cli,
+    server::{self, SetHeader},
+};
+use axum::{
+    body::HttpBody,
+    extract::ConnectInfo,
+    http::{header, HeaderMap, Request, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Router,
+};
+use once_cell::sync::OnceCell;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::time as tokio_time;
+
+static LIMITER: OnceCell<Limiter> = OnceCell::new();
+// Connections are usually short-lived, so a slot frees up soon
+const CONNECTION_RETRY_AFTER: &str = "1";
+
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+impl TokenBucket {
+    fn new(rate: f64, capacity: f64) -> Self {
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+
+    // How long the client has to wait until `amount` tokens are available
+    fn wait_for(&mut self, amount: f64) -> Option<Duration> {
+        self.refill();
+        if self.tokens >= amount {
+            return None;
+        }
+
+        Some(Duration::from_secs_f64((amount - self.tokens) / self.rate))
+    }
+
+    fn take(&mut self, amount: f64) {
+        self.refill();
+        self.tokens -= amount;
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.capacity
+    }
+}
+
+struct ClientBuckets {
+    requests: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+struct Limiter {
+    args: &'static cli::Args,
+    requests_per_sec: Option<u32>,
+    requests_burst: Option<u32>,
+    bytes_per_sec: Option<u64>,
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    clients: Mutex<HashMap<IpAddr, ClientBuckets>>,
+    connections: Mutex<ConnectionCounts>,
+}
+
+#[derive(Default)]
+struct ConnectionCounts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+impl Limiter {
+    fn limits_connections(&self) -> bool {
+        self.max_connections.is_some() || self.max_connections_per_ip.is_some()
+    }
+
+    fn limits_requests(&self) -> bool {
+        self.requests_per_sec.is_some() || self.bytes_per_sec.is_some()
+    }
+
+    fn new_buckets(&self) -> ClientBuckets {
+        ClientBuckets {
+            requests: self.requests_per_sec.map(|rate| {
+                let burst = self.requests_burst.unwrap_or(rate);
+                TokenBucket::new(f64::from(rate), f64::from(burst.max(1)))
+            }),
+            // Clients may receive up to one second worth of bytes at once
+            bytes: self
+                .bytes_per_sec
+                .map(|rate| TokenBucket::new(rate as f64, rate as f64)),
+        }
+    }
+
+    fn check_request(&self, ip: IpAddr) -> Option<Duration> {
+        let mut clients = self.clients.lock().unwrap();
+        let buckets = clients.entry(ip).or_insert_with(|| self.new_buckets());
+
+        // Sending a response while in bandwidth debt is refused until the debt is paid
+        if let Some(wait) = buckets
+            .bytes
+            .as_mut()
+            .and_then(|bucket| bucket.wait_for(0.0))
+        {
+            return Some(wait);
+        }
+        if let Some(bucket) = buckets.requests.as_mut() {
+            if let Some(wait) = bucket.wait_for(1.0) {
+                return Some(wait);
+            }
+            bucket.take(1.0);
+        }
+
+        None
+    }
+
+    fn charge_bytes(&self, ip: IpAddr, bytes: u64) {
+        if let Some(bucket) = self
+            .clients
+            .lock()
+            .unwrap()
+            .get_mut(&ip)
+            .and_then(|buckets| buckets.bytes.as_mut())
+        {
+            bucket.take(bytes as f64);
+        }
+    }
+
+    // Forgets the clients that have been idle long enough for their buckets to refill.
+    // `Option::is_none_or` would need Rust 1.82.
+    #[allow(clippy::unnecessary_map_or)]
+    fn prune(&self) {
+        self.clients.lock().unwrap().retain(|_, buckets| {
+            let requests_full = buckets.requests.as_mut().map_or(true, TokenBucket::is_full);
+            let bytes_full = buckets.bytes.as_mut().map_or(true, TokenBucket::is_full);
+
+            !(requests_full && bytes_full)
+        });
+    }
+
+    // Unix domain socket peers have no IP and only count toward the total
+    fn acquire_connection(&'static self, ip: Option<IpAddr>) -> Option<ConnectionGuard> {
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(max) = self.max_connections {
+            if connections.total >= max {
+                return None;
+            }
+        }
+        if let Some(ip) = ip {
+            let per_ip = connections.per_ip.get(&ip).copied().unwrap_or(0);
+            if let Some(max) = self.max_connections_per_ip {
+                if per_ip >= max {
+                    return None;
+                }
+            }
+            connections.per_ip.insert(ip, per_ip + 1);
+        }
+        connections.total += 1;
+
+        Some(ConnectionGuard { limiter: self, ip })
+    }
+
+    fn release_connection(&self, ip: Option<IpAddr>) {
+        let mut connections = self.connections.lock().unwrap();
+        connections.total = connections.total.saturating_sub(1);
+        if let Some(ip) = ip {
+            if let Some(count) = connections.per_ip.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    connections.per_ip.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+struct ConnectionGuard {
+    limiter: &'static Limiter,
+    ip: Option<IpAddr>,
+}
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.limiter.release_connection(self.ip);
+    }
+}
+
+// A slot of the connection limits, released when the connection gets dropped
+pub struct Slot {
+    _guard: Option<ConnectionGuard>,
+}
+
+// Takes a slot for an accepted connection, before anything is read from it. Connections over the
+// limits get nothing and are served `rejected_app` instead.
+pub fn acquire(addr: Option<SocketAddr>) -> Option<Slot> {
+    match LIMITER.get() {
+        Some(limiter) if limiter.limits_connections() => limiter
+            .acquire_connection(addr.map(|addr| addr.ip()))
+            .map(|guard| Slot {
+                _guard: Some(guard),
+            }),
+        _ => Some(Slot { _guard: None }),
+    }
+}
+
+// Answers every request of a connection over the limits with a 429, without touching the disk
+pub fn rejected_app() -> Router {
+    Router::new().fallback(|| async {
+        (
+            StatusCode::TOO_MANY_REQUESTS,
+            SetHeader(
+                header::RETRY_AFTER.to_string(),
+                String::from(CONNECTION_RETRY_AFTER),
+            ),
+            "Too many connections\n",
+        )
+            .into_response()
+    })
+}
+
+#[derive(Clone)]
+pub struct ClientInfo {
+    // Unix domain socket peers have no address and aren't limited per client, since they're
+    // usually a reverse proxy forwarding the requests of everyone
+    pub addr: Option<SocketAddr>,
+    // The subject of the verified client certificate
+    pub subject: Option<String>,
+}
+impl ClientInfo {
+    pub fn new(addr: Option<SocketAddr>) -> Self {
+        Self {
+            addr,
+            subject: None,
+        }
+    }
+}
+
+async fn too_many_requests(
+    headers: &HeaderMap,
+    uri: &Uri,
+    retry_after: Duration,
+    args: &cli::Args,
+) -> Response {
+    let seconds = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+
+    (
+        SetHeader(header::RETRY_AFTER.to_string(), seconds.to_string()),
+        server::error_response(StatusCode::TOO_MANY_REQUESTS, headers, uri, args).await,
+    )
+        .into_response()
+}
+
+pub async fn middleware<B>(
+    ConnectInfo(client): ConnectInfo<ClientInfo>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let limiter = match LIMITER.get() {
+        Some(limiter) => limiter,
+        None => return next.run(req).await,
+    };
+    // Only the connections are limited by default, which needs no client buckets
+    let ip = match client.addr {
+        Some(addr) if limiter.limits_requests() => addr.ip(),
+        _ => return next.run(req).await,
+    };
+    if let Some(wait) = limiter.check_request(ip) {
+        return too_many_requests(req.headers(), req.uri(), wait, limiter.args).await;
+    }
+
+    let res = next.run(req).await;
+    if limiter.bytes_per_sec.is_some() {
+        let bytes = res.body().size_hint().exact().or_else(|| {
+            res.headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok())
+        });
+        limiter.charge_bytes(ip, bytes.unwrap_or(0));
+    }
+
+    res
+}
+
+pub fn init(args: &'static cli::Args) {
+    let limiter = Limiter {
+        args,
+        requests_per_sec: args.rate_limit,
+        requests_burst: args.rate_limit_burst,
+        bytes_per_sec: args.bandwidth_limit,
+        max_connections: (args.max_connections > 0).then_some(args.max_connections),
+        max_connections_per_ip: args.max_connections_per_ip,
+        clients: Mutex::new(HashMap::new()),
+        connections: Mutex::new(ConnectionCounts::default()),
+    };
+    if !limiter.limits_requests() && !limiter.limits_connections() {
+        return;
+    }
+
+    let limiter = LIMITER.get_or_init(|| limiter);
+    if limiter.limits_requests() {
+        tokio::spawn(async move {
+            loop {
+                tokio_time::sleep(Duration::from_secs(60)).await;
+                limiter.prune();
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wait_for_full_bucket() {
+        let mut bucket = TokenBucket::new(2.0, 4.0);
+        assert_eq!(bucket.wait_for(4.0), None);
+        assert_eq!(bucket.wait_for(0.0), None);
+    }
+
+    #[test]
+    fn wait_for_missing_tokens() {
+        let mut bucket = TokenBucket::new(2.0, 4.0);
+        bucket.take(4.0);
+        let wait = bucket.wait_for(1.0).unwrap();
+        assert!(wait <= Duration::from_millis(500));
+        assert!(wait > Duration::from_millis(400));
+    }
+
+    #[test]
+    fn wait_for_debt() {
+        let mut bucket = TokenBucket::new(100.0, 100.0);
+        bucket.take(300.0);
+        let wait = bucket.wait_for(0.0).unwrap();
+        assert!(wait <= Duration::from_secs(2));
+        assert!(wait > Duration::from_millis(1900));
+    }
+}
@@ -0,0 +1,418 @@
+use crate::{
+    acme, cli,
+    config::{ClientAuth, ListenerConfig, Protocol},
+    http3,
+    limiter::{self, ClientInfo},
+    metrics,
+    timeout::TimeoutStream,
+    x509,
+};
+use axum::{body::Body, extract::ConnectInfo, http::Request, Router};
+use hyper::{
+    server::conn::Http,
+    service::{service_fn, Service},
+};
+use socket2::{Domain, Socket, Type};
+use std::{
+    fmt, fs,
+    io::BufReader,
+    net::{SocketAddr, UdpSocket},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    time as tokio_time,
+};
+use tokio_rustls::{
+    rustls::{
+        server::{
+            AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, NoClientAuth,
+            WantsServerCert,
+        },
+        Certificate, ConfigBuilder, PrivateKey, RootCertStore, ServerConfig,
+    },
+    TlsAcceptor,
+};
+
+// An address the app is served on, with its own TLS and protocol settings
+pub struct Listener {
+    pub addr: SocketAddr,
+    pub protocol: Protocol,
+    pub tls: Option<TlsAcceptor>,
+    // HTTP/3 is served on the same port over UDP
+    pub http3: Option<quinn::ServerConfig>,
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = if self.tls.is_some() { "https" } else { "http" };
+
+        write!(f, "{}://{}", scheme, self.addr)
+    }
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, String> {
+    let file = fs::File::open(path).map_err(|err| err.to_string())?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file)).map_err(|err| err.to_string())?;
+    if certs.is_empty() {
+        return Err(String::from("No certificate found"));
+    }
+
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey, String> {
+    let file = fs::File::open(path).map_err(|err| err.to_string())?;
+    let items =
+        rustls_pemfile::read_all(&mut BufReader::new(file)).map_err(|err| err.to_string())?;
+
+    items
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| String::from("No private key found"))
+}
+
+fn alpn_protocols(protocol: Protocol) -> Vec<Vec<u8>> {
+    match protocol {
+        Protocol::Auto => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+        Protocol::Http1 => vec![b"http/1.1".to_vec()],
+        Protocol::Http2 => vec![b"h2".to_vec()],
+    }
+}
+
+// Client certificates are only asked for when the listener has a CA bundle to verify them with
+fn tls_builder(
+    config: &ListenerConfig,
+) -> Result<ConfigBuilder<ServerConfig, WantsServerCert>, String> {
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let client_ca = match &config.client_ca {
+        Some(client_ca) => client_ca,
+        None => return Ok(builder.with_client_cert_verifier(NoClientAuth::new())),
+    };
+    let invalid = |err: String| {
+        format!(
+            "Invalid client CA bundle: {}! Reason: {}.",
+            client_ca.display(),
+            err
+        )
+    };
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(client_ca).map_err(invalid)? {
+        roots.add(&cert).map_err(|err| invalid(err.to_string()))?;
+    }
+
+    let verifier = match config.client_auth {
+        ClientAuth::Required => AllowAnyAuthenticatedClient::new(roots),
+        ClientAuth::Optional => AllowAnyAnonymousOrAuthenticatedClient::new(roots),
+    };
+
+    Ok(builder.with_client_cert_verifier(verifier))
+}
+
+fn load_tls(
+    cert: &Path,
+    key: &Path,
+    builder: ConfigBuilder<ServerConfig, WantsServerCert>,
+) -> Result<ServerConfig, String> {
+    let certs = load_certs(cert).map_err(|err| {
+        format!(
+            "Invalid TLS certificate: {}! Reason: {}.",
+            cert.display(),
+            err
+        )
+    })?;
+    let key = load_key(key)
+        .map_err(|err| format!("Invalid TLS key: {}! Reason: {}.", key.display(), err))?;
+
+    builder
+        .with_single_cert(certs, key)
+        .map_err(|err| format!("Invalid TLS certificate or key! Reason: {}.", err))
+}
+
+fn new_listener(config: &ListenerConfig, args: &cli::Args) -> Result<Listener, String> {
+    let tls_config = match (&config.tls_cert, &config.tls_key) {
+        (None, None) if config.acme => {
+            if args.config.acme.is_none() {
+                return Err(format!(
+                    "The listener {} uses ACME, but the config file has no [acme] section!",
+                    config.addr
+                ));
+            }
+            Some(acme::server_config(tls_builder(config)?))
+        }
+        (Some(_), _) | (_, Some(_)) if config.acme => {
+            return Err(format!(
+                "The listener {} can't use both ACME and a TLS certificate!",
+                config.addr
+            ))
+        }
+        (Some(cert), Some(key)) => Some(load_tls(cert, key, tls_builder(config)?)?),
+        (None, None) if config.client_ca.is_some() => {
+            return Err(format!(
+                "The listener {} needs TLS to verify client certificates!",
+                config.addr
+            ))
+        }
+        (None, None) => None,
+        _ => {
+            return Err(format!(
+                "The listener {} needs both a TLS certificate and key!",
+                config.addr
+            ))
+        }
+    };
+
+    let http3 = match (&tls_config, config.http3) {
+        (Some(tls_config), true) => Some(http3::server_config(tls_config.clone(), args)),
+        (None, true) => {
+            return Err(format!(
+                "The listener {} needs TLS to serve HTTP/3!",
+                config.addr
+            ))
+        }
+        (_, false) => None,
+    };
+    let tls = tls_config.map(|mut tls_config| {
+        tls_config.alpn_protocols = alpn_protocols(config.protocol);
+        // The certificate of ACME listeners is provided by the ACME client, TLS-ALPN-01
+        // challenges are answered as well
+        if config.acme {
+            tls_config.alpn_protocols.push(acme::ACME_TLS_ALPN.to_vec());
+        }
+
+        TlsAcceptor::from(Arc::new(tls_config))
+    });
+
+    Ok(Listener {
+        addr: config.addr,
+        protocol: config.protocol,
+        tls,
+        http3,
+    })
+}
+
+// The `--addr` options come first, followed by the `[[listeners]]` of the config file
+pub fn load(args: &cli::Args) -> Result<Vec<Listener>, String> {
+    let mut listeners = vec![];
+    for addr in &args.addr {
+        listeners.push(Listener {
+            addr: *addr,
+            protocol: Protocol::Auto,
+            tls: None,
+            http3: None,
+        });
+    }
+    for listener in &args.config.listeners {
+        listeners.push(new_listener(listener, args)?);
+    }
+
+    for (i, listener) in listeners.iter().enumerate() {
+        if listeners[..i]
+            .iter()
+            .any(|other| other.addr == listener.addr)
+        {
+            return Err(format!(
+                "The address {} is listened on more than once!",
+                listener.addr
+            ));
+        }
+    }
+
+    Ok(listeners)
+}
+
+// IPv6 sockets accept IPv4 connections as well on most systems, unless an IPv4 listener
+// already uses the port
+pub fn bind(addr: SocketAddr, listeners: &[Listener]) -> Result<TcpListener, String> {
+    let only_v6 = addr.is_ipv6()
+        && listeners
+            .iter()
+            .any(|listener| listener.addr.is_ipv4() && listener.addr.port() == addr.port());
+    let bind = || -> std::io::Result<TcpListener> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        if addr.is_ipv6() {
+            socket.set_only_v6(only_v6)?;
+        }
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+
+        TcpListener::from_std(socket.into())
+    };
+
+    bind().map_err(|err| format!("Unable to bind on: {}! Reason: {}.", addr, err))
+}
+
+// The UDP socket of an HTTP/3 listener, sharing the port with IPv4 listeners like `bind`
+pub fn bind_udp(addr: SocketAddr, listeners: &[Listener]) -> Result<UdpSocket, String> {
+    let only_v6 = addr.is_ipv6()
+        && listeners.iter().any(|listener| {
+            listener.http3.is_some()
+                && listener.addr.is_ipv4()
+                && listener.addr.port() == addr.port()
+        });
+    let bind = || -> std::io::Result<UdpSocket> {
+        let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, None)?;
+        if addr.is_ipv6() {
+            socket.set_only_v6(only_v6)?;
+        }
+        socket.bind(&addr.into())?;
+
+        Ok(socket.into())
+    };
+
+    bind().map_err(|err| format!("Unable to bind on: {}/udp! Reason: {}.", addr, err))
+}
+
+// A timeout of 0 seconds disables it
+pub fn timeout(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+// Wraps the stream before any TLS handshake, so slow handshakes time out as well
+fn with_timeouts<I>(stream: I, args: &cli::Args) -> TimeoutStream<I> {
+    TimeoutStream::new(
+        stream,
+        timeout(args.idle_timeout),
+        timeout(args.write_timeout),
+    )
+}
+
+fn http(protocol: Protocol, args: &cli::Args) -> Http {
+    let mut http = Http::new();
+    if let Some(timeout) = timeout(args.header_read_timeout) {
+        http.http1_header_read_timeout(timeout);
+    }
+    http.max_buf_size(args.max_header_size as usize)
+        .http2_max_header_list_size(args.max_header_size);
+    match protocol {
+        Protocol::Auto => {}
+        Protocol::Http1 => {
+            http.http1_only(true);
+        }
+        Protocol::Http2 => {
+            http.http2_only(true);
+        }
+    }
+
+    http
+}
+
+// Connections without a slot get a 429 for their requests instead, HTTP/1 ones are closed after the
+// first response
+async fn serve_connection<I>(
+    stream: I,
+    mut app: Router,
+    client: ClientInfo,
+    slot: Option<limiter::Slot>,
+    protocol: Protocol,
+    args: &cli::Args,
+) where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut http = http(protocol, args);
+    // Errors of a single connection, like clients going away, don't concern the server
+    match slot {
+        Some(_slot) => {
+            let _connection = metrics::ConnectionGuard::new();
+            let service = service_fn(move |mut req: Request<Body>| {
+                req.extensions_mut().insert(ConnectInfo(client.clone()));
+                app.call(req)
+            });
+            let _ = http.serve_connection(stream, service).await;
+        }
+        None => {
+            http.http1_keep_alive(false);
+            let _ = http.serve_connection(stream, limiter::rejected_app()).await;
+        }
+    }
+}
+
+pub async fn serve(
+    tcp_listener: TcpListener,
+    listener: Listener,
+    app: Router,
+    args: &'static cli::Args,
+) {
+    loop {
+        let (stream, addr) = match tcp_listener.accept().await {
+            Ok(connection) => connection,
+            // Usually caused by running out of file descriptors, retrying immediately would spin
+            Err(_) => {
+                tokio_time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let slot = limiter::acquire(Some(addr));
+        let mut client = ClientInfo::new(Some(addr));
+        let app = app.clone();
+        let protocol = listener.protocol;
+        let tls = listener.tls.clone();
+        let stream = with_timeouts(stream, args);
+        tokio::spawn(async move {
+            match tls {
+                Some(tls) => {
+                    if let Ok(stream) = tls.accept(stream).await {
+                        let connection = stream.get_ref().1;
+                        // TLS-ALPN-01 validations end with the handshake
+                        if connection.alpn_protocol() == Some(acme::ACME_TLS_ALPN) {
+                            return;
+                        }
+                        // Only certificates verified against the client CA are presented
+                        client.subject = connection
+                            .peer_certificates()
+                            .and_then(|certs| certs.first())
+                            .and_then(|cert| x509::subject(&cert.0));
+                        serve_connection(stream, app, client, slot, protocol, args).await;
+                    }
+                }
+                None => serve_connection(stream, app, client, slot, protocol, args).await,
+            }
+        });
+    }
+}
+
+// Unix domain socket peers only count toward `--max-connections`, the limits per client IP don't
+// apply to them
+#[cfg(unix)]
+pub async fn serve_unix(
+    unix_listener: tokio::net::UnixListener,
+    app: Router,
+    args: &'static cli::Args,
+) {
+    loop {
+        let stream = match unix_listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(_) => {
+                tokio_time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let slot = limiter::acquire(None);
+        let app = app.clone();
+        tokio::spawn(async move {
+            let stream = with_timeouts(stream, args);
+            serve_connection(
+                stream,
+                app,
+                ClientInfo::new(None),
+                slot,
+                Protocol::Auto,
+                args,
+            )
+            .await
+        });
+    }
+}
@@ -1,8 +1,35 @@
 use once_cell::sync::Lazy;
 
+mod access;
+mod access_log;
+mod acme;
 pub(crate) mod cli;
+mod client_certs;
 mod compile;
+mod config;
+mod custom_headers;
+mod health;
+mod hosts;
+mod http3;
+mod https;
+mod limiter;
+mod listener;
+mod markdown;
+mod metrics;
+mod mime_types;
+mod mounts;
+mod redirects;
 mod server;
+mod ssi;
+#[cfg(unix)]
+mod systemd;
+mod timefmt;
+mod timeout;
+#[cfg(unix)]
+mod unix;
+mod upload;
+mod webdav;
+mod x509;
 
 #[tokio::main]
 async fn main() {
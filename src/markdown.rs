@@ -0,0 +1,279 @@
+use crate::{cli, metrics};
+use axum::http::{header, HeaderMap, Uri};
+use once_cell::sync::Lazy;
+use pulldown_cmark::{
+    escape::{escape_href, escape_html},
+    html, CodeBlockKind, Event, HeadingLevel, Options, Parser, Tag,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+use syntect::{
+    highlighting::{Theme, ThemeSet},
+    html::highlighted_html_for_string,
+    parsing::SyntaxSet,
+};
+
+static SYNTAXES: Lazy<SyntaxSet> = Lazy::new(SyntaxSet::load_defaults_newlines);
+static THEME: Lazy<Theme> = Lazy::new(|| {
+    ThemeSet::load_defaults()
+        .themes
+        .remove("InspiredGitHub")
+        .unwrap()
+});
+// Rendered pages are only rendered again after their file got modified
+static PAGES: Lazy<Mutex<HashMap<PathBuf, (SystemTime, String)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+const DEFAULT_TEMPLATE: &str = "<!DOCTYPE html>
+<html lang='en'>
+<head>
+<meta charset='utf-8'>
+<meta name='viewport' content='width=device-width, initial-scale=1'>
+<title>{{title}}</title>
+<style>
+body { margin: 0; font-family: system-ui, sans-serif; line-height: 1.6; background: #fff; color: #1f2328; }
+main { max-width: 48rem; margin: 0 auto; padding: 2rem 1rem; }
+h1, h2 { padding-bottom: 0.3em; border-bottom: 1px solid #d8dee4; }
+h1, h2, h3, h4, h5, h6 { position: relative; }
+a { color: #0969da; }
+.anchor { margin-left: 0.4em; color: #8c959f; text-decoration: none; visibility: hidden; }
+h1:hover .anchor, h2:hover .anchor, h3:hover .anchor, h4:hover .anchor, h5:hover .anchor, h6:hover .anchor { visibility: visible; }
+code { padding: 0.2em 0.4em; font-size: 85%; background: #eff1f3; border-radius: 6px; }
+pre { padding: 1rem; overflow: auto; font-size: 85%; line-height: 1.45; background: #f6f8fa; border-radius: 6px; }
+pre code { padding: 0; font-size: 100%; background: none; }
+blockquote { margin: 0; padding: 0 1em; color: #57606a; border-left: 0.25em solid #d0d7de; }
+table { border-collapse: collapse; }
+th, td { padding: 6px 13px; border: 1px solid #d0d7de; }
+img { max-width: 100%; }
+</style>
+</head>
+<body>
+<main>
+{{content}}
+</main>
+</body>
+</html>
+";
+
+pub fn is_markdown(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("md") || ext.eq_ignore_ascii_case("markdown"))
+}
+
+// Browsers asking for HTML get the rendered page, unless the source is asked for with `?raw`
+pub fn wants_html(headers: &HeaderMap, uri: &Uri) -> bool {
+    let raw = uri.query().is_some_and(|query| {
+        query
+            .split('&')
+            .any(|param| param == "raw" || param.starts_with("raw="))
+    });
+    let accepts_html = headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media_type| {
+            let mut params = media_type.split(';').map(str::trim);
+            params
+                .next()
+                .is_some_and(|media_type| media_type.eq_ignore_ascii_case("text/html"))
+                && !params.any(|param| {
+                    param
+                        .strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        .is_some_and(|q| q == 0.0)
+                })
+        });
+
+    accepts_html && !raw
+}
+
+pub fn load_template(path: &Path) -> Result<String, String> {
+    let template = fs::read_to_string(path).map_err(|err| {
+        format!(
+            "Failed to read the Markdown template '{}'! Reason: {}.",
+            path.display(),
+            err
+        )
+    })?;
+    if !template.contains("{{content}}") {
+        return Err(format!(
+            "The Markdown template '{}' doesn't contain '{{{{content}}}}'!",
+            path.display()
+        ));
+    }
+
+    Ok(template)
+}
+
+// The README of a directory, whatever its case
+pub fn readme(dir: &Path) -> Option<String> {
+    fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| name.eq_ignore_ascii_case("README.md"))
+        .min()
+}
+
+// GitHub style ids: lowercase, with spaces turned into dashes and punctuation left out
+fn slug(text: &str) -> String {
+    text.trim()
+        .chars()
+        .filter_map(|c| match c {
+            ' ' => Some('-'),
+            c if c.is_alphanumeric() || c == '-' || c == '_' => Some(c),
+            _ => None,
+        })
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+// Repeated headings get `-1`, `-2` and so on appended
+fn unique_id(id: String, ids: &mut HashSet<String>) -> String {
+    let mut unique = id.clone();
+    let mut i = 0;
+    while !ids.insert(unique.clone()) {
+        i += 1;
+        unique = format!("{}-{}", id, i);
+    }
+
+    unique
+}
+
+// The events up to the end of the current block, which is left out
+fn until_end<'a>(events: &mut impl Iterator<Item = Event<'a>>) -> Vec<Event<'a>> {
+    let mut depth = 0;
+    let mut inner = vec![];
+    for event in events {
+        match event {
+            Event::Start(_) => depth += 1,
+            Event::End(_) if depth == 0 => break,
+            Event::End(_) => depth -= 1,
+            _ => {}
+        }
+        inner.push(event);
+    }
+
+    inner
+}
+
+fn text(events: &[Event]) -> String {
+    events
+        .iter()
+        .filter_map(|event| match event {
+            Event::Text(text) | Event::Code(text) => Some(text.as_ref()),
+            _ => None,
+        })
+        .collect()
+}
+
+// Renders the Markdown into the template, titled by its first top-level heading
+pub fn render(source: &str, name: &str, args: &cli::Args) -> String {
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_HEADING_ATTRIBUTES;
+    let mut events = Parser::new_ext(source, options);
+    let mut out = vec![];
+    let mut ids = HashSet::new();
+    let mut title = None;
+    while let Some(event) = events.next() {
+        match event {
+            Event::Start(Tag::Heading(level, id, classes)) => {
+                let inner = until_end(&mut events);
+                let text = text(&inner);
+                if level == HeadingLevel::H1 && title.is_none() {
+                    title = Some(text.clone());
+                }
+                let id = unique_id(id.map_or_else(|| slug(&text), String::from), &mut ids);
+
+                let mut start = format!("<{} id=\"", level);
+                let _ = escape_html(&mut start, &id);
+                if !classes.is_empty() {
+                    start.push_str("\" class=\"");
+                    let _ = escape_html(&mut start, &classes.join(" "));
+                }
+                start.push_str("\">");
+                let mut end = String::from("<a class=\"anchor\" href=\"#");
+                let _ = escape_href(&mut end, &id);
+                end.push_str(&format!("\" aria-hidden=\"true\">#</a></{}>\n", level));
+
+                out.push(Event::Html(start.into()));
+                out.extend(inner);
+                out.push(Event::Html(end.into()));
+            }
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) => {
+                let inner = until_end(&mut events);
+                let token = info.split([' ', ',', '{']).next().unwrap_or_default();
+                let highlighted = SYNTAXES.find_syntax_by_token(token).and_then(|syntax| {
+                    highlighted_html_for_string(&text(&inner), &SYNTAXES, syntax, &THEME).ok()
+                });
+                match highlighted {
+                    Some(highlighted) => out.push(Event::Html(highlighted.into())),
+                    // Unknown languages are left to the renderer
+                    None => {
+                        out.push(Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(
+                            info.clone(),
+                        ))));
+                        out.extend(inner);
+                        out.push(Event::End(Tag::CodeBlock(CodeBlockKind::Fenced(info))));
+                    }
+                }
+            }
+            event => out.push(event),
+        }
+    }
+
+    let mut content = String::with_capacity(source.len() * 2);
+    html::push_html(&mut content, out.into_iter());
+    let mut title_html = String::new();
+    let _ = escape_html(
+        &mut title_html,
+        &title.unwrap_or_else(|| String::from(name)),
+    );
+
+    // The content is put in last, so it can't be mistaken for a placeholder
+    let template = args.markdown_page.as_deref().unwrap_or(DEFAULT_TEMPLATE);
+    let (before, after) = template.split_once("{{content}}").unwrap();
+    format!(
+        "{}{}{}",
+        before.replace("{{title}}", &title_html),
+        content,
+        after.replace("{{title}}", &title_html)
+    )
+}
+
+// Rendered pages of files are cached until the files get modified
+pub fn render_file(file_path: &Path, source: &[u8], args: &cli::Args) -> String {
+    let modified = fs::metadata(file_path).and_then(|metadata| metadata.modified());
+    if let Ok(modified) = modified {
+        if let Some((cached_at, page)) = PAGES.lock().unwrap().get(file_path) {
+            if *cached_at == modified {
+                metrics::record_cache("markdown", true);
+                return page.clone();
+            }
+        }
+    }
+    metrics::record_cache("markdown", false);
+
+    let name = file_path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    let page = render(&String::from_utf8_lossy(source), &name, args);
+    if let Ok(modified) = modified {
+        PAGES
+            .lock()
+            .unwrap()
+            .insert(file_path.to_path_buf(), (modified, page.clone()));
+    }
+
+    page
+}
@@ -0,0 +1,312 @@
+use crate::{cli, hosts, mounts, server};
+use axum::{
+    body::HttpBody,
+    http::{header, Method, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use once_cell::sync::Lazy;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+// Upper bounds of the latency histogram buckets in seconds
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+#[derive(Default)]
+struct Metrics {
+    // By method and status
+    requests: Mutex<BTreeMap<(&'static str, u16), u64>>,
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    // In microseconds
+    latency_sum: AtomicU64,
+    latency_count: AtomicU64,
+    bytes_sent: AtomicU64,
+    // By cache name and whether it was a hit
+    cache: Mutex<BTreeMap<(&'static str, bool), u64>>,
+    open_connections: AtomicU64,
+    // By host name and mount prefix
+    mounts: Mutex<BTreeMap<(String, String), MountStats>>,
+}
+
+#[derive(Default)]
+struct MountStats {
+    requests: u64,
+    bytes_sent: u64,
+}
+
+pub fn record_cache(cache: &'static str, hit: bool) {
+    *METRICS
+        .cache
+        .lock()
+        .unwrap()
+        .entry((cache, hit))
+        .or_insert(0) += 1;
+}
+
+// Counts a connection as open until dropped
+pub struct ConnectionGuard;
+impl ConnectionGuard {
+    pub fn new() -> Self {
+        METRICS.open_connections.fetch_add(1, Ordering::Relaxed);
+
+        Self
+    }
+}
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        METRICS.open_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// The host and mount that serve the request path, before any rewrites
+fn mount_labels<B>(req: &Request<B>, args: &cli::Args) -> (String, String) {
+    let host = hosts::find(
+        &args.hosts,
+        hosts::host_name(req.headers(), req.uri()).as_deref(),
+    );
+    let path = server::strip_base_path(req.uri().path(), args).unwrap_or_default();
+    let (mount, _) = mounts::find(&host.mounts, path);
+
+    let host_name = host
+        .names
+        .first()
+        .cloned()
+        .unwrap_or_else(|| String::from("default"));
+    let prefix = if mount.prefix.is_empty() {
+        String::from("/")
+    } else {
+        mount.prefix.clone()
+    };
+
+    (host_name, prefix)
+}
+
+// Clients can send any extension method, which would grow the metrics without bound
+fn method_label(method: &Method) -> &'static str {
+    match method.as_str() {
+        "GET" => "GET",
+        "HEAD" => "HEAD",
+        "POST" => "POST",
+        "PUT" => "PUT",
+        "DELETE" => "DELETE",
+        "CONNECT" => "CONNECT",
+        "OPTIONS" => "OPTIONS",
+        "TRACE" => "TRACE",
+        "PATCH" => "PATCH",
+        "PROPFIND" => "PROPFIND",
+        _ => "OTHER",
+    }
+}
+
+pub async fn middleware<B>(req: Request<B>, next: Next<B>, args: &'static cli::Args) -> Response {
+    let method = method_label(req.method());
+    let labels = mount_labels(&req, args);
+    let started = Instant::now();
+
+    let res = next.run(req).await;
+
+    let elapsed = started.elapsed().as_secs_f64();
+    let bytes = res.body().size_hint().exact().or_else(|| {
+        res.headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+    });
+    let bytes = bytes.unwrap_or(0);
+
+    *METRICS
+        .requests
+        .lock()
+        .unwrap()
+        .entry((method, res.status().as_u16()))
+        .or_insert(0) += 1;
+    for (i, bound) in LATENCY_BUCKETS.iter().enumerate() {
+        if elapsed <= *bound {
+            METRICS.latency_buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    METRICS
+        .latency_sum
+        .fetch_add((elapsed * 1_000_000.0) as u64, Ordering::Relaxed);
+    METRICS.latency_count.fetch_add(1, Ordering::Relaxed);
+    METRICS.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+
+    let mut mounts = METRICS.mounts.lock().unwrap();
+    let stats = mounts.entry(labels).or_default();
+    stats.requests += 1;
+    stats.bytes_sent += bytes;
+    drop(mounts);
+
+    res
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+// Renders the metrics in the Prometheus text format
+fn render() -> String {
+    let mut out = String::new();
+
+    header(
+        &mut out,
+        "sfhs_requests_total",
+        "counter",
+        "Number of handled requests.",
+    );
+    for ((method, status), count) in METRICS.requests.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "sfhs_requests_total{{method=\"{}\",status=\"{}\"}} {}",
+            escape(method),
+            status,
+            count
+        );
+    }
+
+    header(
+        &mut out,
+        "sfhs_request_duration_seconds",
+        "histogram",
+        "Time taken to respond to requests.",
+    );
+    for (i, bound) in LATENCY_BUCKETS.iter().enumerate() {
+        let _ = writeln!(
+            out,
+            "sfhs_request_duration_seconds_bucket{{le=\"{}\"}} {}",
+            bound,
+            METRICS.latency_buckets[i].load(Ordering::Relaxed)
+        );
+    }
+    let count = METRICS.latency_count.load(Ordering::Relaxed);
+    let _ = writeln!(
+        out,
+        "sfhs_request_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+        count
+    );
+    let _ = writeln!(
+        out,
+        "sfhs_request_duration_seconds_sum {}",
+        METRICS.latency_sum.load(Ordering::Relaxed) as f64 / 1_000_000.0
+    );
+    let _ = writeln!(out, "sfhs_request_duration_seconds_count {}", count);
+
+    header(
+        &mut out,
+        "sfhs_response_bytes_total",
+        "counter",
+        "Number of response body bytes sent.",
+    );
+    let _ = writeln!(
+        out,
+        "sfhs_response_bytes_total {}",
+        METRICS.bytes_sent.load(Ordering::Relaxed)
+    );
+
+    header(
+        &mut out,
+        "sfhs_cache_requests_total",
+        "counter",
+        "Number of cache lookups by result.",
+    );
+    for ((cache, hit), count) in METRICS.cache.lock().unwrap().iter() {
+        let result = if *hit { "hit" } else { "miss" };
+        let _ = writeln!(
+            out,
+            "sfhs_cache_requests_total{{cache=\"{}\",result=\"{}\"}} {}",
+            cache, result, count
+        );
+    }
+
+    header(
+        &mut out,
+        "sfhs_open_connections",
+        "gauge",
+        "Number of open connections.",
+    );
+    let _ = writeln!(
+        out,
+        "sfhs_open_connections {}",
+        METRICS.open_connections.load(Ordering::Relaxed)
+    );
+
+    let mounts = METRICS.mounts.lock().unwrap();
+    header(
+        &mut out,
+        "sfhs_mount_requests_total",
+        "counter",
+        "Number of handled requests by host and mount.",
+    );
+    for ((host, mount), stats) in mounts.iter() {
+        let _ = writeln!(
+            out,
+            "sfhs_mount_requests_total{{host=\"{}\",mount=\"{}\"}} {}",
+            escape(host),
+            escape(mount),
+            stats.requests
+        );
+    }
+    header(
+        &mut out,
+        "sfhs_mount_response_bytes_total",
+        "counter",
+        "Number of response body bytes sent by host and mount.",
+    );
+    for ((host, mount), stats) in mounts.iter() {
+        let _ = writeln!(
+            out,
+            "sfhs_mount_response_bytes_total{{host=\"{}\",mount=\"{}\"}} {}",
+            escape(host),
+            escape(mount),
+            stats.bytes_sent
+        );
+    }
+
+    out
+}
+
+pub async fn handler() -> Response {
+    (
+        server::SetHeader(
+            header::CONTENT_TYPE.to_string(),
+            String::from("text/plain; version=0.0.4; charset=utf-8"),
+        ),
+        render(),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn method_labels() {
+        assert_eq!(method_label(&Method::GET), "GET");
+        assert_eq!(
+            method_label(&Method::from_bytes(b"PROPFIND").unwrap()),
+            "PROPFIND"
+        );
+        assert_eq!(method_label(&Method::from_bytes(b"FOO1").unwrap()), "OTHER");
+        assert_eq!(method_label(&Method::from_bytes(b"get").unwrap()), "OTHER");
+    }
+}
@@ -0,0 +1,273 @@
+use crate::cli;
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::OsStr,
+    fs,
+    io::Read,
+    path::Path,
+};
+
+// How many bytes are looked at to guess the type of files without a known extension
+const SNIFF_LEN: usize = 512;
+
+// File signatures and the types they stand for
+const MAGIC_NUMBERS: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"\0asm", "application/wasm"),
+    (b"wOFF", "font/woff"),
+    (b"wOF2", "font/woff2"),
+];
+
+// Extensions mapped to MIME types, taking precedence over the built-in ones
+#[derive(Debug, Clone, Default)]
+pub struct MimeTypes {
+    types: HashMap<String, String>,
+}
+
+impl MimeTypes {
+    fn insert(&mut self, ext: &str, mime: &str) -> Result<(), String> {
+        let ext = ext.trim_start_matches('.').to_ascii_lowercase();
+        if ext.is_empty() || !mime.contains('/') || mime.contains(char::is_whitespace) {
+            return Err(format!(
+                "Invalid MIME type '{}' for the extension '{}'",
+                mime, ext
+            ));
+        }
+
+        self.types.insert(ext, String::from(mime));
+        Ok(())
+    }
+
+    // Reads a `mime.types` file of Apache or nginx, made of lines with a type followed by its
+    // extensions
+    fn parse_file(&mut self, content: &str) -> Result<(), String> {
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let line = line.trim().trim_end_matches(';');
+            // The `types { ... }` block of nginx
+            if line.is_empty() || line.ends_with('{') || line == "}" {
+                continue;
+            }
+
+            let mut words = line.split_whitespace();
+            if let Some(mime) = words.next() {
+                for ext in words {
+                    self.insert(ext, mime)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // The entries of the config file win over the ones of the `mime.types` file
+    pub fn load(file: Option<&Path>, config: &BTreeMap<String, String>) -> Result<Self, String> {
+        let mut mime_types = Self::default();
+        if let Some(file) = file {
+            let content = fs::read_to_string(file).map_err(|err| {
+                format!(
+                    "Failed to read the MIME types file '{}'! Reason: {}.",
+                    file.display(),
+                    err
+                )
+            })?;
+            mime_types.parse_file(&content)?;
+        }
+        for (ext, mime) in config {
+            mime_types.insert(ext, mime)?;
+        }
+
+        Ok(mime_types)
+    }
+}
+
+// Types that are text, even though they aren't `text/*`
+fn is_text(mime: &str) -> bool {
+    let essence = mime.split(';').next().unwrap_or_default().trim();
+
+    essence.starts_with("text/")
+        || essence.ends_with("+xml")
+        || essence.ends_with("+json")
+        || matches!(
+            essence,
+            "application/javascript" | "application/json" | "application/xml"
+        )
+}
+
+// Appends the charset to text types that don't specify one
+pub fn with_charset(mime: String, args: &cli::Args) -> String {
+    if args.charset == "none" || !is_text(&mime) || mime.contains("charset=") {
+        return mime;
+    }
+
+    format!("{}; charset={}", mime, args.charset)
+}
+
+fn sniff(head: &[u8]) -> &'static str {
+    if let Some((_, mime)) = MAGIC_NUMBERS
+        .iter()
+        .find(|(magic, _)| head.starts_with(magic))
+    {
+        return mime;
+    }
+    if head.len() >= 12 && head.starts_with(b"RIFF") && &head[8..12] == b"WEBP" {
+        return "image/webp";
+    }
+
+    // The head may end in the middle of a character
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        Err(err) if err.error_len().is_none() => {
+            std::str::from_utf8(&head[..err.valid_up_to()]).unwrap_or_default()
+        }
+        Err(_) => return "application/octet-stream",
+    };
+    if text
+        .chars()
+        .any(|c| c.is_control() && !matches!(c, '\t' | '\n' | '\r' | '\x0c'))
+    {
+        return "application/octet-stream";
+    }
+
+    let start = text.trim_start_matches('\u{feff}').trim_start();
+    let lowercase = start
+        .chars()
+        .take(16)
+        .collect::<String>()
+        .to_ascii_lowercase();
+    if lowercase.starts_with("<!doctype html") || lowercase.starts_with("<html") {
+        "text/html"
+    } else if lowercase.starts_with("<svg") {
+        "image/svg+xml"
+    } else if lowercase.starts_with("<?xml") {
+        "application/xml"
+    } else {
+        "text/plain"
+    }
+}
+
+// The type of a file by its extension, if the extension is known
+fn by_extension(path: &Path, args: &cli::Args) -> Option<String> {
+    let ext = path
+        .extension()
+        .and_then(OsStr::to_str)?
+        .to_ascii_lowercase();
+
+    args.mime_types.types.get(&ext).cloned().or_else(|| {
+        new_mime_guess::from_ext(&ext)
+            .first()
+            .map(|mime| mime.to_string())
+    })
+}
+
+// The Content-Type of a file given its contents, which are only looked at when the extension
+// doesn't tell
+pub fn content_type(path: &Path, contents: &[u8], args: &cli::Args) -> String {
+    let mime = by_extension(path, args)
+        .unwrap_or_else(|| String::from(sniff(&contents[..contents.len().min(SNIFF_LEN)])));
+
+    with_charset(mime, args)
+}
+
+// Like `content_type`, but only reads the beginning of the file when needed
+pub fn of_file(path: &Path, args: &cli::Args) -> String {
+    if let Some(mime) = by_extension(path, args) {
+        return with_charset(mime, args);
+    }
+
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    if let Ok(file) = fs::File::open(path) {
+        let _ = file.take(SNIFF_LEN as u64).read_to_end(&mut head);
+    }
+
+    content_type(path, &head, args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn args(charset: &str) -> cli::Args {
+        cli::Args::try_parse_from(["static-file-http-server", ".", "--charset", charset]).unwrap()
+    }
+
+    #[test]
+    fn parse_apache_and_nginx_files() {
+        let mut mime_types = MimeTypes::default();
+        mime_types
+            .parse_file(
+                "# Apache\napplication/wasm\t\twasm\ntext/x-custom  cst .CST2 # trailing\n\
+                 types {\n    application/manifest+json  webmanifest;\n}\n",
+            )
+            .unwrap();
+
+        assert_eq!(mime_types.types["wasm"], "application/wasm");
+        assert_eq!(mime_types.types["cst"], "text/x-custom");
+        assert_eq!(mime_types.types["cst2"], "text/x-custom");
+        assert_eq!(mime_types.types["webmanifest"], "application/manifest+json");
+        assert_eq!(mime_types.types.len(), 4);
+    }
+
+    #[test]
+    fn parse_invalid_types() {
+        assert!(MimeTypes::default().parse_file("wasm wasm").is_err());
+        assert!(MimeTypes::default().insert("", "text/plain").is_err());
+        assert!(MimeTypes::default().insert("txt", "text/ plain").is_err());
+    }
+
+    #[test]
+    fn config_overrides_file() {
+        let mut config = BTreeMap::new();
+        config.insert(String::from(".WASM"), String::from("application/x-wasm"));
+        let mime_types = MimeTypes::load(None, &config).unwrap();
+
+        assert_eq!(mime_types.types["wasm"], "application/x-wasm");
+    }
+
+    #[test]
+    fn sniff_contents() {
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n...."), "image/png");
+        assert_eq!(sniff(b"RIFF\0\0\0\0WEBPVP8 "), "image/webp");
+        assert_eq!(sniff(b"\xef\xbb\xbf  <!DOCTYPE html><html>"), "text/html");
+        assert_eq!(
+            sniff(b"<svg xmlns='http://www.w3.org/2000/svg'>"),
+            "image/svg+xml"
+        );
+        assert_eq!(sniff(b"<?xml version='1.0'?>"), "application/xml");
+        assert_eq!(sniff("plain text \u{e9}".as_bytes()), "text/plain");
+        // Cut in the middle of a character
+        assert_eq!(sniff(&"caf\u{e9}".as_bytes()[..4]), "text/plain");
+        assert_eq!(sniff(b"\0\x01\x02"), "application/octet-stream");
+    }
+
+    #[test]
+    fn charsets() {
+        assert_eq!(
+            with_charset(String::from("text/css"), &args("utf-8")),
+            "text/css; charset=utf-8"
+        );
+        assert_eq!(
+            with_charset(String::from("application/ld+json"), &args("iso-8859-1")),
+            "application/ld+json; charset=iso-8859-1"
+        );
+        assert_eq!(
+            with_charset(String::from("image/png"), &args("utf-8")),
+            "image/png"
+        );
+        assert_eq!(
+            with_charset(String::from("text/html"), &args("none")),
+            "text/html"
+        );
+        assert_eq!(
+            with_charset(String::from("text/plain; charset=ascii"), &args("utf-8")),
+            "text/plain; charset=ascii"
+        );
+    }
+}
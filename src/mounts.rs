@@ -0,0 +1,131 @@
+use crate::{access::Ignore, cli, server};
+use std::path::{Path, PathBuf};
+
+// A directory served under a URL prefix
+#[derive(Debug, Clone)]
+pub struct Mount {
+    // Empty for the root mount, otherwise starts with '/' and has no trailing slash
+    pub prefix: String,
+    pub path: PathBuf,
+    pub index: String,
+    pub spa: bool,
+    pub cache: i64,
+    pub cors: String,
+    // The `.sfhsignore` patterns of the directory
+    pub ignore: Ignore,
+}
+
+fn normalize_prefix(prefix: &str) -> Result<String, String> {
+    let normalized = prefix.trim_end_matches('/');
+    if !prefix.starts_with('/') {
+        return Err(format!("The mount prefix '{}' must start with '/'", prefix));
+    }
+    if normalized.is_empty() {
+        return Err(String::from(
+            "The served directory is already mounted at '/', use a different prefix",
+        ));
+    }
+    if normalized.split('/').any(|segment| segment == "..") {
+        return Err(format!("Invalid mount prefix '{}'", prefix));
+    }
+
+    Ok(String::from(normalized))
+}
+
+fn check_directory(path: &Path) -> Result<(), String> {
+    if !path.is_dir() {
+        return Err(format!(
+            "The mounted path '{}' is not a directory",
+            path.display()
+        ));
+    }
+
+    Ok(())
+}
+
+// The served directory is mounted at `/` followed by the `--mount` options and the `[[mounts]]` of
+// the config file
+pub fn load(args: &cli::Args) -> Result<Vec<Mount>, String> {
+    let mut mounts = vec![Mount {
+        prefix: String::new(),
+        path: args.path.clone(),
+        index: server::root_serve(args.path.to_str().unwrap()),
+        spa: args.spa,
+        cache: args.cache,
+        cors: args.cors.clone(),
+        ignore: Ignore::load(&args.path)?,
+    }];
+
+    for mount in &args.mount {
+        let (prefix, path) = match mount.split_once('=') {
+            Some(mount) => mount,
+            None => {
+                return Err(format!(
+                    "Invalid mount '{}', expected a PREFIX=DIRECTORY pair",
+                    mount
+                ))
+            }
+        };
+        let path = PathBuf::from(path);
+        check_directory(&path)?;
+
+        mounts.push(Mount {
+            prefix: normalize_prefix(prefix)?,
+            index: server::root_serve(path.to_str().unwrap()),
+            ignore: Ignore::load(&path)?,
+            path,
+            spa: args.spa,
+            cache: args.cache,
+            cors: args.cors.clone(),
+        });
+    }
+
+    for mount in &args.config.mounts {
+        check_directory(&mount.path)?;
+
+        mounts.push(Mount {
+            prefix: normalize_prefix(&mount.prefix)?,
+            path: mount.path.clone(),
+            index: match &mount.index {
+                Some(index) => index.clone(),
+                None => server::root_serve(mount.path.to_str().unwrap()),
+            },
+            spa: mount.spa.unwrap_or(args.spa),
+            cache: mount.cache.unwrap_or(args.cache),
+            cors: mount.cors.clone().unwrap_or_else(|| args.cors.clone()),
+            ignore: Ignore::load(&mount.path)?,
+        });
+    }
+
+    for (i, mount) in mounts.iter().enumerate() {
+        if mounts[..i].iter().any(|other| other.prefix == mount.prefix) {
+            return Err(format!("'{}' is mounted more than once", mount.prefix));
+        }
+    }
+
+    Ok(mounts)
+}
+
+// Finds the mount with the longest prefix matching the path and returns the path relative to it
+pub fn find<'a>(mounts: &'a [Mount], path: &str) -> (&'a Mount, String) {
+    let path = path.trim_start_matches('/');
+
+    mounts
+        .iter()
+        .filter_map(|mount| {
+            let prefix = mount.prefix.trim_start_matches('/');
+            if prefix.is_empty() {
+                return Some((mount, String::from(path)));
+            }
+
+            match path.strip_prefix(prefix) {
+                Some("") => Some((mount, String::new())),
+                Some(rest) => rest
+                    .strip_prefix('/')
+                    .map(|rest| (mount, String::from(rest))),
+                None => None,
+            }
+        })
+        .max_by_key(|(mount, _)| mount.prefix.len())
+        .unwrap_or_else(|| (&mounts[0], String::from(path)))
+}
@@ -0,0 +1,406 @@
+use crate::config::RedirectConfig;
+use axum::http::StatusCode;
+use percent_encoding::percent_decode_str;
+use std::{collections::HashMap, fmt, fs, iter, path::Path};
+
+const REDIRECT_STATUSES: [u16; 5] = [301, 302, 303, 307, 308];
+const REWRITE_STATUSES: [u16; 2] = [200, 404];
+
+// A single rule of a Netlify compatible `_redirects` file, for example:
+// `/blog/:year/*  /posts/:year/:splat  301!`
+#[derive(Debug, Clone)]
+pub struct Rule {
+    from: String,
+    query: Vec<(String, String)>,
+    to: String,
+    status: u16,
+    force: bool,
+}
+
+#[derive(Debug)]
+pub enum Action {
+    Redirect {
+        status: StatusCode,
+        location: String,
+    },
+    Rewrite {
+        status: StatusCode,
+        path: String,
+    },
+}
+
+// Matches a path against a pattern made of `:placeholder` segments and an optional trailing `/*`.
+// Returns the values of the placeholders and the splat.
+pub fn match_path(pattern: &str, path: &str) -> Option<HashMap<String, String>> {
+    let mut params = HashMap::new();
+
+    let pattern = pattern.trim_end_matches('/');
+    let path = path.trim_end_matches('/');
+    let (pattern, splat) = match pattern.strip_suffix("/*") {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+    let mut pattern_segments = pattern.split('/').skip(1);
+    let mut path_segments = path.split('/').skip(1);
+    loop {
+        match (pattern_segments.next(), path_segments.next()) {
+            (Some(pattern), Some(segment)) => match pattern.strip_prefix(':') {
+                Some(name) if !segment.is_empty() => {
+                    params.insert(String::from(name), String::from(segment));
+                }
+                Some(_) => return None,
+                None if pattern == segment => {}
+                None => return None,
+            },
+            (None, Some(segment)) if splat => {
+                let splat: Vec<&str> = iter::once(segment).chain(path_segments).collect();
+                params.insert(String::from("splat"), splat.join("/"));
+                break;
+            }
+            (None, None) => {
+                if splat {
+                    params.insert(String::from("splat"), String::new());
+                }
+                break;
+            }
+            _ => return None,
+        }
+    }
+
+    Some(params)
+}
+
+fn is_condition(token: &str) -> bool {
+    token.contains('=') && !token.starts_with('/') && !token.starts_with("http")
+}
+
+impl Rule {
+    pub fn new(
+        from: impl ToString,
+        query: Vec<(String, String)>,
+        to: impl ToString,
+        status: u16,
+        force: bool,
+    ) -> Result<Self, String> {
+        let from = from.to_string();
+        let to = to.to_string();
+        if !from.starts_with('/') {
+            return Err(format!("The source path '{}' must start with '/'", from));
+        }
+        if REWRITE_STATUSES.contains(&status) {
+            if !to.starts_with('/') {
+                return Err(format!(
+                    "Rewrites with status {} can only point to local paths, found '{}'",
+                    status, to
+                ));
+            }
+        } else if !REDIRECT_STATUSES.contains(&status) {
+            return Err(format!("Unsupported status code {}", status));
+        }
+
+        Ok(Self {
+            from,
+            query,
+            to,
+            status,
+            force,
+        })
+    }
+
+    pub fn parse(line: &str) -> Result<Option<Self>, String> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+
+        let mut tokens = line.split_whitespace().peekable();
+        let from = tokens.next().unwrap_or_default();
+        let mut query = vec![];
+        while let Some(token) = tokens.next_if(|token| is_condition(token)) {
+            let (name, value) = token.split_once('=').unwrap_or_default();
+            query.push((String::from(name), String::from(value)));
+        }
+        let to = match tokens.next() {
+            Some(to) => to,
+            None => return Err(format!("Missing the destination of '{}'", from)),
+        };
+        let (status, force) = match tokens.next() {
+            Some(status) => {
+                let (status, force) = match status.strip_suffix('!') {
+                    Some(status) => (status, true),
+                    None => (status, false),
+                };
+                match status.parse::<u16>() {
+                    Ok(status) => (status, force),
+                    Err(_) => return Err(format!("Invalid status code '{}'", status)),
+                }
+            }
+            None => (301, false),
+        };
+        if let Some(condition) = tokens.next() {
+            return Err(format!("Unsupported condition '{}'", condition));
+        }
+
+        Self::new(from, query, to, status, force).map(Some)
+    }
+
+    fn matches(
+        &self,
+        path: &str,
+        query: &HashMap<String, String>,
+    ) -> Option<HashMap<String, String>> {
+        let mut params = match_path(&self.from, path)?;
+        for (name, expected) in &self.query {
+            let value = query.get(name)?;
+            match expected.strip_prefix(':') {
+                Some(placeholder) => {
+                    params.insert(String::from(placeholder), value.clone());
+                }
+                None if expected == value => {}
+                None => return None,
+            }
+        }
+
+        Some(params)
+    }
+
+    fn target(&self, params: &HashMap<String, String>) -> String {
+        let mut out = String::new();
+        let mut rest = self.to.as_str();
+        while let Some(index) = rest.find(':') {
+            out.push_str(&rest[..index]);
+            rest = &rest[index + 1..];
+
+            let name_len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            let name = &rest[..name_len];
+            match params.get(name) {
+                Some(value) if name.starts_with(|c: char| c.is_ascii_alphabetic()) => {
+                    out.push_str(value);
+                    rest = &rest[name_len..];
+                }
+                _ => out.push(':'),
+            }
+        }
+        out.push_str(rest);
+
+        out
+    }
+}
+
+// Serializes the rule back to the `_redirects` syntax
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.from)?;
+        for (name, value) in &self.query {
+            write!(f, " {}={}", name, value)?;
+        }
+        write!(f, " {} {}", self.to, self.status)?;
+        if self.force {
+            write!(f, "!")?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn parse(contents: &str) -> Result<Vec<Rule>, String> {
+    let mut rules = vec![];
+    for (i, line) in contents.lines().enumerate() {
+        match Rule::parse(line) {
+            Ok(Some(rule)) => rules.push(rule),
+            Ok(None) => {}
+            Err(err) => return Err(format!("Line {}: {}", i + 1, err)),
+        }
+    }
+
+    Ok(rules)
+}
+
+fn decode(value: &str) -> String {
+    percent_decode_str(&value.replace('+', " "))
+        .decode_utf8_lossy()
+        .to_string()
+}
+
+fn parse_query(query: Option<&str>) -> HashMap<String, String> {
+    query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((name, value)) => (decode(name), decode(value)),
+            None => (decode(pair), String::new()),
+        })
+        .collect()
+}
+
+// Finds the first matching rule. Rules that aren't forced are shadowed by existing files.
+pub fn resolve(
+    rules: &[Rule],
+    path: &str,
+    query: Option<&str>,
+    file_exists: impl Fn(&str) -> bool,
+) -> Option<Action> {
+    let query_params = parse_query(query);
+    let (rule, params) = rules.iter().find_map(|rule| {
+        rule.matches(path, &query_params)
+            .map(|params| (rule, params))
+    })?;
+    if !rule.force && file_exists(path) {
+        return None;
+    }
+
+    let status = StatusCode::from_u16(rule.status).ok()?;
+    let mut target = rule.target(&params);
+    if REWRITE_STATUSES.contains(&rule.status) {
+        if let Some((path, _)) = target.split_once('?') {
+            target = String::from(path);
+        }
+
+        return Some(Action::Rewrite {
+            status,
+            path: target,
+        });
+    }
+
+    // Query strings are passed through unless the rule matched on them
+    if let Some(query) = query {
+        if rule.query.is_empty() && !target.contains('?') && !query.is_empty() {
+            target = format!("{}?{}", target, query);
+        }
+    }
+
+    Some(Action::Redirect {
+        status,
+        location: target,
+    })
+}
+
+pub const REDIRECTS_FILE: &str = "_redirects";
+
+// Rules of the `_redirects` file in the served directory come first, followed by the config file rules
+pub fn load(dir: &Path, redirects: &[RedirectConfig]) -> Result<Vec<Rule>, String> {
+    let mut rules = vec![];
+
+    let redirects_file = dir.join(REDIRECTS_FILE);
+    if redirects_file.is_file() {
+        let parsed = fs::read_to_string(&redirects_file)
+            .map_err(|err| err.to_string())
+            .and_then(|contents| parse(&contents))
+            .map_err(|err| format!("Invalid `{}` file! {}", redirects_file.display(), err))?;
+        rules.extend(parsed);
+    }
+
+    for redirect in redirects {
+        let query = redirect
+            .query
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        let rule = Rule::new(
+            &redirect.from,
+            query,
+            &redirect.to,
+            redirect.status,
+            redirect.force,
+        )
+        .map_err(|err| format!("Invalid redirect in the config file! {}", err))?;
+        rules.push(rule);
+    }
+
+    Ok(rules)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redirect(action: Option<Action>) -> Option<(u16, String)> {
+        match action? {
+            Action::Redirect { status, location } => Some((status.as_u16(), location)),
+            Action::Rewrite { .. } => None,
+        }
+    }
+
+    #[test]
+    fn match_placeholders_and_splat() {
+        let params = match_path("/blog/:year/*", "/blog/2023/01/hello").unwrap();
+        assert_eq!(params["year"], "2023");
+        assert_eq!(params["splat"], "01/hello");
+
+        let params = match_path("/blog/*", "/blog").unwrap();
+        assert_eq!(params["splat"], "");
+        assert!(match_path("/blog/:year", "/blog/").is_none());
+        assert!(match_path("/blog/:year", "/blog/2023/01").is_none());
+        assert!(match_path("/blog", "/news").is_none());
+        assert!(match_path("/blog/", "/blog").is_some());
+    }
+
+    #[test]
+    fn parse_rules() {
+        let rule = Rule::parse("/old  /new").unwrap().unwrap();
+        assert_eq!(rule.to_string(), "/old /new 301");
+
+        let rule = Rule::parse("/search q=:q  /find/:q  302!")
+            .unwrap()
+            .unwrap();
+        assert_eq!(rule.to_string(), "/search q=:q /find/:q 302!");
+
+        assert!(Rule::parse("  # comment").unwrap().is_none());
+        assert!(Rule::parse("").unwrap().is_none());
+    }
+
+    #[test]
+    fn parse_invalid_rules() {
+        assert!(Rule::parse("/old").is_err());
+        assert!(Rule::parse("old /new").is_err());
+        assert!(Rule::parse("/old /new abc").is_err());
+        assert!(Rule::parse("/old /new 500").is_err());
+        assert!(Rule::parse("/old https://example.com/ 200").is_err());
+        assert!(Rule::parse("/old /new 301 Country=us").is_err());
+
+        let err = parse("/a /b\n/c").unwrap_err();
+        assert!(err.starts_with("Line 2:"));
+    }
+
+    #[test]
+    fn resolve_redirects() {
+        let rules =
+            parse("/blog/:year/*  /posts/:year/:splat\n/search q=:q  /find/:q  302").unwrap();
+
+        assert_eq!(
+            redirect(resolve(&rules, "/blog/2023/hello", Some("ref=rss"), |_| {
+                false
+            })),
+            Some((301, String::from("/posts/2023/hello?ref=rss")))
+        );
+        assert_eq!(
+            redirect(resolve(&rules, "/search", Some("q=cats+dogs"), |_| false)),
+            Some((302, String::from("/find/cats dogs")))
+        );
+        assert!(resolve(&rules, "/search", None, |_| false).is_none());
+    }
+
+    #[test]
+    fn existing_files_shadow_rules_unless_forced() {
+        let rules = parse("/a  /b\n/c  /d  301!").unwrap();
+
+        assert!(resolve(&rules, "/a", None, |_| true).is_none());
+        assert!(resolve(&rules, "/c", None, |_| true).is_some());
+    }
+
+    #[test]
+    fn resolve_rewrites() {
+        let rules = parse("/app/*  /index.html?x=1  200").unwrap();
+
+        match resolve(&rules, "/app/settings", None, |_| false) {
+            Some(Action::Rewrite { status, path }) => {
+                assert_eq!(status, StatusCode::OK);
+                assert_eq!(path, "/index.html");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -1,25 +1,38 @@
-use crate::cli;
+use crate::{
+    access::{self, Access},
+    access_log, acme, cli, client_certs, config, custom_headers, health, hosts, http3, https,
+    limiter, listener, markdown, metrics, mime_types,
+    mounts::{self, Mount},
+    redirects::{self, Action},
+    ssi, upload, webdav,
+};
+#[cfg(unix)]
+use crate::{systemd, unix};
 use axum::{
-    extract::Path as AxumPath,
+    extract::{multipart::MultipartRejection, DefaultBodyLimit, Multipart, RawBody},
     http::{
-        header::{HeaderName, HeaderValue},
-        StatusCode,
+        header::{self, HeaderName, HeaderValue},
+        HeaderMap, Method, StatusCode, Uri,
     },
+    middleware,
     response::{IntoResponse, IntoResponseParts, Response, ResponseParts},
     routing::get,
     Router,
 };
+use once_cell::sync::Lazy;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
 use std::{
-    ffi::OsStr,
-    net::SocketAddr,
+    collections::HashMap,
+    io::ErrorKind,
     path::{Path, PathBuf},
     str::FromStr,
-    time::Duration,
+    sync::Mutex,
+    time::SystemTime,
 };
-use tokio::{fs, net as tokio_net, time as tokio_time};
+use tokio::{fs, net as tokio_net, task};
 
 // Hypothetical helper type for setting a single header
-struct SetHeader(String, String);
+pub(crate) struct SetHeader(pub String, pub String);
 impl IntoResponseParts for SetHeader {
     type Error = (StatusCode, String);
 
@@ -74,60 +87,499 @@ fn set_cache_control(cache_control: &i64) -> Option<SetHeader> {
 }
 
 // 1.HTTP status code 2.Content-Type header 3.CORS header 4.Cache-Control header 5.Body
-type HandlerReturnType = (StatusCode, SetHeader, SetHeader, Option<SetHeader>, String);
+pub(crate) type HandlerReturnType = (StatusCode, SetHeader, SetHeader, Option<SetHeader>, Vec<u8>);
+
+// Characters escaped in the path segments of generated URLs
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+pub(crate) fn encode_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| utf8_percent_encode(segment, PATH_SEGMENT).to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// Includes are read with blocking calls and highlighting large Markdown files takes a while, so
+// both are rendered away from the async workers
+async fn render_blocking(render: impl FnOnce() -> Vec<u8> + Send + 'static) -> Option<Vec<u8>> {
+    task::spawn_blocking(render).await.ok()
+}
 
-async fn handler(path: String, args: &cli::Args) -> HandlerReturnType {
-    let public_dir = String::from(args.path.to_str().unwrap());
-    let path = if public_dir.ends_with("/") {
+async fn handler(
+    path: String,
+    mount: &'static Mount,
+    headers: &HeaderMap,
+    uri: &Uri,
+    args: &'static cli::Args,
+) -> HandlerReturnType {
+    let public_dir = String::from(mount.path.to_str().unwrap());
+    let file_path = if public_dir.ends_with("/") {
         format!("{}{}", public_dir, path)
     } else {
         format!("{}/{}", public_dir, path)
     };
 
-    match fs::read_to_string(&path).await {
+    match fs::read(&file_path).await {
         Ok(contents) => {
-            let content_type = match Path::new(&path).extension().and_then(OsStr::to_str) {
-                Some(ext) => new_mime_guess::from_ext(ext)
-                    .first_or_text_plain()
-                    .to_string(),
-                None => String::from("text/plain"),
+            let mut content_type = mime_types::content_type(Path::new(&file_path), &contents, args);
+            let rendered = if ssi::is_ssi(&path) {
+                let (document, uri) = (path.clone(), uri.clone());
+                render_blocking(move || ssi::render(&contents, &document, mount, &uri, args)).await
+            } else if args.markdown
+                && markdown::is_markdown(&path)
+                && markdown::wants_html(headers, uri)
+            {
+                content_type = String::from("text/html; charset=utf-8");
+                let file_path = PathBuf::from(&file_path);
+                render_blocking(move || {
+                    markdown::render_file(&file_path, &contents, args).into_bytes()
+                })
+                .await
+            } else {
+                Some(contents)
+            };
+            let contents = match rendered {
+                Some(contents) => contents,
+                None => {
+                    return error_page(StatusCode::INTERNAL_SERVER_ERROR, &path, mount, args).await
+                }
             };
 
             (
                 StatusCode::OK,
                 set_content_type(content_type),
-                set_cors_access(&args.cors),
-                set_cache_control(&args.cache),
-                String::from(contents),
+                set_cors_access(&mount.cors),
+                set_cache_control(&mount.cache),
+                contents,
             )
         }
-        Err(_) => {
-            let body = match PathBuf::from_str(
-                format!("{}/404.html", args.path.to_str().unwrap()).as_str(),
-            ) {
-                Ok(path) => {
-                    if path.exists() {
-                        fs::read_to_string(path).await.unwrap()
-                    } else {
-                        String::from("File not found!")
-                    }
-                }
-                Err(_) => String::from("File not found!"),
+        Err(err) => {
+            let status = match err.kind() {
+                ErrorKind::NotFound => StatusCode::NOT_FOUND,
+                ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+                _ if Path::new(&file_path).is_dir() => StatusCode::NOT_FOUND,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
             };
 
-            (
-                StatusCode::NOT_FOUND,
-                set_content_type("text/html"),
-                set_cors_access(&args.cors),
-                set_cache_control(&args.cache),
-                body,
+            error_page(status, &path, mount, args).await
+        }
+    }
+}
+
+static ERROR_PAGES: Lazy<Mutex<HashMap<PathBuf, (SystemTime, String)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Error pages are read once and only read again after getting modified
+async fn read_error_page(path: &Path) -> Option<String> {
+    let metadata = fs::metadata(path).await.ok()?;
+    if !metadata.is_file() {
+        return None;
+    }
+    let modified = metadata.modified().ok()?;
+    if let Some((cached_at, contents)) = ERROR_PAGES.lock().unwrap().get(path) {
+        if *cached_at == modified {
+            metrics::record_cache("error_pages", true);
+            return Some(contents.clone());
+        }
+    }
+    metrics::record_cache("error_pages", false);
+
+    let contents = fs::read_to_string(path).await.ok()?;
+    ERROR_PAGES
+        .lock()
+        .unwrap()
+        .insert(path.to_path_buf(), (modified, contents.clone()));
+
+    Some(contents)
+}
+
+pub(crate) fn default_error_page(status: StatusCode) -> String {
+    let reason = status.canonical_reason().unwrap_or("Error");
+
+    format!(
+        "<!DOCTYPE html>
+<html lang='en'>
+<head>
+<meta charset='utf-8'>
+<meta name='viewport' content='width=device-width, initial-scale=1'>
+<title>{code} {reason}</title>
+<style>
+body {{ margin: 0; min-height: 100vh; display: flex; align-items: center; justify-content: center; font-family: system-ui, sans-serif; background: #f6f7f9; color: #1f2328; }}
+main {{ text-align: center; }}
+h1 {{ margin: 0; font-size: 5rem; font-weight: 700; color: #57606a; }}
+p {{ margin: 0.5rem 0 0; font-size: 1.25rem; }}
+</style>
+</head>
+<body>
+<main>
+<h1>{code}</h1>
+<p>{reason}</p>
+</main>
+</body>
+</html>
+",
+        code = status.as_u16(),
+        reason = reason,
+    )
+}
+
+// Looks for `<status>.html` in the directory of the requested path and then its parents up to the
+// mounted directory, falling back to a built-in page
+pub(crate) async fn error_page(
+    status: StatusCode,
+    path: &str,
+    mount: &Mount,
+    args: &cli::Args,
+) -> HandlerReturnType {
+    let file_name = format!("{}.html", status.as_u16());
+    let dir = if path.is_empty() || path.ends_with('/') {
+        Path::new(path.trim_end_matches('/'))
+    } else {
+        Path::new(path).parent().unwrap_or(Path::new(""))
+    };
+
+    // Pages in protected directories, or behind symlinks that aren't followed, are skipped
+    let mut body = None;
+    for dir in dir.ancestors() {
+        let page = match dir.to_str() {
+            Some("") => file_name.clone(),
+            Some(dir) => format!("{}/{}", dir, file_name),
+            None => continue,
+        };
+        if access::check(mount, &page, false, args) != Access::Allowed {
+            continue;
+        }
+        body = read_error_page(&mount.path.join(&page)).await;
+        if body.is_some() {
+            break;
+        }
+    }
+    let body = body.unwrap_or_else(|| default_error_page(status));
+
+    // Only missing files are cached, other errors are likely temporary
+    let cache = if status == StatusCode::NOT_FOUND {
+        mount.cache
+    } else {
+        -1
+    };
+
+    (
+        status,
+        set_content_type(mime_types::with_charset(String::from("text/html"), args)),
+        set_cors_access(&mount.cors),
+        set_cache_control(&cache),
+        body.into_bytes(),
+    )
+}
+
+// Finds the error page of the host and mount serving the request
+pub(crate) async fn error_response(
+    status: StatusCode,
+    headers: &HeaderMap,
+    uri: &Uri,
+    args: &cli::Args,
+) -> HandlerReturnType {
+    let host = hosts::find(&args.hosts, hosts::host_name(headers, uri).as_deref());
+    let path = strip_base_path(uri.path(), args).unwrap_or_default();
+    let (mount, path) = mounts::find(&host.mounts, path);
+
+    error_page(status, &path, mount, args).await
+}
+
+async fn method_not_allowed(headers: HeaderMap, uri: Uri, args: &cli::Args) -> HandlerReturnType {
+    error_response(StatusCode::METHOD_NOT_ALLOWED, &headers, &uri, args).await
+}
+
+// Methods without a route of their own, like WebDAV's `PROPFIND`, end up here
+async fn other_method(method: Method, headers: HeaderMap, uri: Uri, args: &cli::Args) -> Response {
+    if args.webdav && method.as_str() == "PROPFIND" {
+        return webdav::propfind(headers, uri, args).await;
+    }
+
+    method_not_allowed(headers, uri, args).await.into_response()
+}
+
+// Strips the base path from the request path. Paths outside of the base path are rejected.
+pub(crate) fn strip_base_path<'a>(path: &'a str, args: &cli::Args) -> Option<&'a str> {
+    let base_path = match &args.base_path {
+        Some(base_path) => base_path,
+        None => return Some(path),
+    };
+
+    match path.strip_prefix(base_path.as_str()) {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => Some(rest),
+        _ => None,
+    }
+}
+
+fn with_base_path(location: String, args: &cli::Args) -> String {
+    match &args.base_path {
+        Some(base_path) if location.starts_with('/') && !location.starts_with("//") => {
+            format!("{}{}", base_path, location)
+        }
+        _ => location,
+    }
+}
+
+// Decodes the request path and strips its leading slash. Paths escaping the served directory are rejected.
+pub(crate) fn relative_path(path: &str) -> Option<String> {
+    let path = percent_decode_str(path).decode_utf8().ok()?;
+    let path = path.trim_start_matches('/');
+    if path.split(['/', '\\']).any(|segment| segment == "..") {
+        return None;
+    }
+
+    Some(String::from(path))
+}
+
+async fn serve(headers: HeaderMap, uri: Uri, args: &'static cli::Args) -> Response {
+    let host = hosts::find(&args.hosts, hosts::host_name(&headers, &uri).as_deref());
+    let request_path = match strip_base_path(uri.path(), args) {
+        Some(request_path) => request_path,
+        None if uri.path() == "/" => {
+            return redirect(
+                StatusCode::MOVED_PERMANENTLY,
+                String::from("/"),
+                &host.mounts[0],
+                args,
             )
         }
+        None => {
+            return error_page(StatusCode::NOT_FOUND, "", &host.mounts[0], args)
+                .await
+                .into_response()
+        }
+    };
+    let path = match relative_path(request_path) {
+        Some(path) if path != redirects::REDIRECTS_FILE => path,
+        _ => {
+            return error_page(StatusCode::NOT_FOUND, "", &host.mounts[0], args)
+                .await
+                .into_response()
+        }
+    };
+
+    let file_exists = |path: &str| {
+        let (mount, path) = mounts::find(&host.mounts, path);
+        let resolved = resolve_path(&path, mount, args);
+        mount.path.join(&resolved.file).is_file()
+            && access::check(mount, &resolved.file, false, args) == Access::Allowed
+    };
+    let (status, path, rewritten) =
+        match redirects::resolve(&host.rules, &format!("/{}", path), uri.query(), file_exists) {
+            Some(Action::Redirect { status, location }) => {
+                return redirect(status, location, &host.mounts[0], args);
+            }
+            Some(Action::Rewrite { status, path }) => match relative_path(&path) {
+                Some(path) => (status, path, true),
+                None => {
+                    return error_page(StatusCode::NOT_FOUND, "", &host.mounts[0], args)
+                        .await
+                        .into_response()
+                }
+            },
+            None => (StatusCode::OK, path, false),
+        };
+
+    let (mount, path) = mounts::find(&host.mounts, &path);
+    let resolved = resolve_path(&path, mount, args);
+    let is_dir = mount.path.join(&resolved.file).is_dir();
+    if let Some(status) = access::check(mount, &resolved.file, is_dir, args).status() {
+        return error_page(status, &path, mount, args).await.into_response();
+    }
+    let canonical = match resolved.canonical {
+        Some(canonical) => Some(format!("{}{}", mount.prefix, canonical)),
+        // Mounted directories are always served with a trailing slash
+        None if path.is_empty() && !request_path.ends_with('/') => {
+            Some(format!("{}/", mount.prefix))
+        }
+        None => None,
+    };
+    if let (Some(canonical), false) = (canonical, rewritten) {
+        let location = match uri.query() {
+            Some(query) => format!("{}?{}", canonical, query),
+            None => canonical,
+        };
+
+        return redirect(StatusCode::MOVED_PERMANENTLY, location, mount, args);
+    }
+
+    // Directories are served their index file, or else their README to browsers
+    let (index, readme) = if args.markdown && (path.is_empty() || is_dir) {
+        let dir = if path.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", resolved.file.trim_end_matches('/'))
+        };
+        let allowed = |file: &String| {
+            mount.path.join(file).is_file()
+                && access::check(mount, file, false, args) == Access::Allowed
+        };
+        match INDEX_FILES
+            .iter()
+            .map(|index_file| format!("{}{}", dir, index_file))
+            .find(allowed)
+        {
+            Some(index) => (Some(index), None),
+            None => (
+                None,
+                markdown::readme(&mount.path.join(&dir))
+                    .map(|readme| format!("{}{}", dir, readme))
+                    .filter(allowed),
+            ),
+        }
+    } else {
+        (None, None)
+    };
+    let vary = readme.is_some() || (args.markdown && markdown::is_markdown(&resolved.file));
+    let page = index.or_else(|| readme.filter(|_| markdown::wants_html(&headers, &uri)));
+    if page.is_some() && !path.is_empty() && !request_path.ends_with('/') && !rewritten {
+        // Relative links of the page only work below the directory
+        let location = match uri.query() {
+            Some(query) => format!("{}/{}/?{}", mount.prefix, encode_path(&path), query),
+            None => format!("{}/{}/", mount.prefix, encode_path(&path)),
+        };
+
+        return redirect(StatusCode::MOVED_PERMANENTLY, location, mount, args);
+    }
+
+    let mut res = handler(resolved.file, mount, &headers, &uri, args).await;
+    if res.0 == StatusCode::NOT_FOUND
+        && mount.spa
+        && Path::new(&path).extension().is_none()
+        && access::check(mount, &mount.index, false, args) == Access::Allowed
+    {
+        res = handler(mount.index.clone(), mount, &headers, &uri, args).await;
+    }
+    if let (StatusCode::NOT_FOUND, Some(page)) = (res.0, page) {
+        res = handler(page, mount, &headers, &uri, args).await;
+    }
+    if res.0 == StatusCode::OK {
+        res.0 = status;
+    }
+
+    let mut res = res.into_response();
+    // The same URL is rendered or not depending on the `Accept` header
+    if vary {
+        res.headers_mut()
+            .append(header::VARY, HeaderValue::from_static("Accept"));
+    }
+
+    res
+}
+
+// Local locations are prefixed with the base path
+fn redirect(status: StatusCode, location: String, mount: &Mount, args: &cli::Args) -> Response {
+    (
+        status,
+        SetHeader(String::from("Location"), with_base_path(location, args)),
+        set_cors_access(&mount.cors),
+    )
+        .into_response()
+}
+
+struct ResolvedPath {
+    file: String,
+    // The path the request should be redirected to for clean URLs
+    canonical: Option<String>,
+}
+
+// Maps the request path to a file of the mount. With clean URLs, `/about` is served from
+// `about.html` or `about/index.html` and `/about.html` is redirected to `/about`.
+fn resolve_path(path: &str, mount: &Mount, args: &cli::Args) -> ResolvedPath {
+    if path.is_empty() {
+        return ResolvedPath {
+            file: mount.index.clone(),
+            canonical: None,
+        };
+    }
+    if !args.clean_urls {
+        return ResolvedPath {
+            file: String::from(path),
+            canonical: None,
+        };
+    }
+
+    let is_file =
+        |path: &str| mount.path.join(path).is_file() && access::symlinks_allowed(mount, path, args);
+    let page = |file: String, dir: bool| {
+        let trimmed = path.trim_end_matches('/');
+        let with_slash = match args.trailing_slash {
+            Some(cli::TrailingSlash::Always) => true,
+            Some(cli::TrailingSlash::Never) => false,
+            None if dir => true,
+            None => path.ends_with('/'),
+        };
+        let canonical = if with_slash {
+            format!("/{}/", trimmed)
+        } else {
+            format!("/{}", trimmed)
+        };
+
+        ResolvedPath {
+            file,
+            canonical: (canonical != format!("/{}", path)).then_some(canonical),
+        }
+    };
+
+    let trimmed = path.trim_end_matches('/');
+    if let Some(stem) = trimmed.strip_suffix(".html") {
+        if is_file(trimmed) {
+            let (clean, dir) = match stem.strip_suffix("index") {
+                Some(dir) if dir.is_empty() || dir.ends_with('/') => {
+                    (dir.trim_end_matches('/'), true)
+                }
+                _ => (stem, false),
+            };
+            let canonical = match (clean.is_empty(), dir, &args.trailing_slash) {
+                (true, _, _) => String::from("/"),
+                (false, true, Some(cli::TrailingSlash::Never)) => format!("/{}", clean),
+                (false, true, _) => format!("/{}/", clean),
+                (false, false, Some(cli::TrailingSlash::Always)) => format!("/{}/", clean),
+                (false, false, _) => format!("/{}", clean),
+            };
+
+            return ResolvedPath {
+                file: String::from(trimmed),
+                canonical: Some(canonical),
+            };
+        }
+    }
+    if !path.ends_with('/') && is_file(path) {
+        return ResolvedPath {
+            file: String::from(path),
+            canonical: None,
+        };
+    }
+    let html_file = format!("{}.html", trimmed);
+    if is_file(&html_file) {
+        return page(html_file, false);
+    }
+    for index_file in INDEX_FILES {
+        let index_file = format!("{}/{}", trimmed, index_file);
+        if is_file(&index_file) {
+            return page(index_file, true);
+        }
+    }
+
+    ResolvedPath {
+        file: String::from(path),
+        canonical: None,
     }
 }
 
 const INDEX_FILES: [&str; 4] = ["index.html", "index.htm", "index.xhtml", "index.shtml"];
-fn root_serve(parent: &str) -> String {
+pub(crate) fn root_serve(parent: &str) -> String {
     let parent = if !parent.ends_with("/") {
         format!("{}/", parent)
     } else {
@@ -151,62 +603,267 @@ fn root_serve(parent: &str) -> String {
     String::from(out.unwrap())
 }
 
-async fn addr_is_available_or_exit(addr: SocketAddr) {
-    let listener = tokio_net::TcpListener::bind(addr).await;
-    match listener {
-        Ok(_) => {}
-        Err(err) => {
-            cli::unrecoverable_clap_error_with_cmd(format!(
-                "Unable to bind on: {}! Reason: {}.",
-                addr.to_string(),
-                err.to_string()
-            ));
+pub async fn run(args: &'static cli::Args) {
+    let serve = move |headers: HeaderMap, uri: Uri| serve(headers, uri, args);
+    let other_method = move |method: Method, headers: HeaderMap, uri: Uri| {
+        other_method(method, headers, uri, args)
+    };
+    let mut methods = get(serve);
+    if args.allow_upload {
+        methods = methods
+            .put(
+                move |headers: HeaderMap, uri: Uri, RawBody(body): RawBody| {
+                    upload::put(headers, uri, body, args)
+                },
+            )
+            .post(
+                move |headers: HeaderMap,
+                      uri: Uri,
+                      multipart: Result<Multipart, MultipartRejection>| {
+                    upload::post(headers, uri, multipart, args)
+                },
+            );
+    }
+    if args.allow_delete {
+        methods =
+            methods.delete(move |headers: HeaderMap, uri: Uri| upload::delete(headers, uri, args));
+    }
+    if args.webdav {
+        methods = methods.options(move || webdav::options(args));
+    }
+    let methods = methods.fallback(other_method);
+    let mut app = Router::new()
+        .route("/", methods.clone())
+        .route("/*path", methods);
+    // Uploads enforce `--max-upload-size` themselves
+    if args.allow_upload {
+        app = app.layer(DefaultBodyLimit::disable());
+    }
+    if args.metrics && args.metrics_addr.is_none() {
+        app = app.route("/metrics", get(metrics::handler));
+    }
+    // Layers only wrap the routes added before them, so uncounted health checks come last
+    let health = Router::new()
+        .route(&args.health_path, get(health::healthz))
+        .route(&args.ready_path, get(move || health::readyz(args)));
+    if args.health_checks && args.count_health_checks {
+        app = app.merge(health.clone());
+    }
+    if !args.config.client_certs.is_empty() {
+        app = app.layer(middleware::from_fn(move |req, next| {
+            client_certs::middleware(req, next, args)
+        }));
+    }
+    app = app.layer(middleware::from_fn(limiter::middleware));
+    if args.metrics {
+        app = app.layer(middleware::from_fn(move |req, next| {
+            metrics::middleware(req, next, args)
+        }));
+    }
+    if args.health_checks && !args.count_health_checks {
+        app = app.merge(health);
+    }
+    if !args.header_rules.is_empty() {
+        app = app.layer(middleware::from_fn(move |req, next| {
+            custom_headers::middleware(req, next, args)
+        }));
+    }
+    // ACME challenges are answered on every host and outside of the base path
+    if args.config.acme.is_some() {
+        app = app.layer(middleware::from_fn(acme::http_challenge));
+    }
+    if args.access_log {
+        app = app.layer(middleware::from_fn(move |req, next| {
+            access_log::middleware(req, next, args)
+        }));
+    }
+
+    let mut listeners = match listener::load(args) {
+        Ok(listeners) => listeners,
+        Err(err) => return cli::unrecoverable_clap_error_with_cmd(err),
+    };
+    // Sockets passed by systemd are used instead of binding the same address
+    #[cfg(unix)]
+    let mut activated = systemd::listeners();
+    let mut bound = vec![];
+    for listener in &listeners {
+        #[cfg(unix)]
+        if let Some(tcp_listener) = activated.take_tcp(listener.addr) {
+            bound.push(
+                tokio_net::TcpListener::from_std(tcp_listener).map_err(|err| err.to_string()),
+            );
+            continue;
         }
+
+        bound.push(listener::bind(listener.addr, &listeners));
+    }
+    // The other sockets passed by systemd serve plain HTTP
+    #[cfg(unix)]
+    for (addr, tcp_listener) in activated.tcp.drain(..) {
+        listeners.push(listener::Listener {
+            addr,
+            protocol: config::Protocol::Auto,
+            tls: None,
+            http3: None,
+        });
+        bound.push(tokio_net::TcpListener::from_std(tcp_listener).map_err(|err| err.to_string()));
+    }
+    let bound: Vec<tokio_net::TcpListener> = match bound.into_iter().collect() {
+        Ok(bound) => bound,
+        Err(err) => return cli::unrecoverable_clap_error_with_cmd(err),
     };
-}
+    let mut names: Vec<String> = listeners
+        .iter()
+        .map(|listener| listener.to_string())
+        .collect();
 
-pub async fn run(args: &'static cli::Args) {
-    let root_serve = root_serve(args.path.to_str().unwrap());
-    let app = Router::new()
-        .route("/", get(move || handler(root_serve, &args)))
-        .route(
-            "/:filename",
-            get(move |AxumPath(path): AxumPath<String>| handler(path, &args)),
-        );
-
-    tokio::spawn(async {
-        let mut i = 0;
-        loop {
-            tokio_time::sleep(Duration::from_secs(2)).await;
-
-            let addr = args.addr.to_string();
-            let url = format!("http://{}", &addr);
-            if let Ok(resp) = reqwest::get(url).await {
-                if resp.status() == 200 || resp.status() == 404 {
-                    if args.open {
-                        open::that(&addr).unwrap_or_else(|err| {
-                            println!("Failed to open the browser. Reason: {}", err.to_string());
-                        });
-                    }
-
-                    println!("Server has started. Listening on {}.", &addr);
-                    break;
+    limiter::init(args);
+    let mut tasks = vec![];
+    #[cfg(unix)]
+    {
+        let mut unix_listeners = vec![];
+        if let Some(path) = &args.unix {
+            let unix_listener = match activated.take_unix(path) {
+                Some(unix_listener) => {
+                    tokio_net::UnixListener::from_std(unix_listener).map_err(|err| err.to_string())
                 }
-            }
+                None => unix::bind(path, args.unix_mode),
+            };
+            unix_listeners.push((path.display().to_string(), unix_listener));
+        }
+        for (path, unix_listener) in activated.unix.drain(..) {
+            let name = match path {
+                Some(path) => path.display().to_string(),
+                None => String::from("an unnamed Unix socket"),
+            };
+            let unix_listener =
+                tokio_net::UnixListener::from_std(unix_listener).map_err(|err| err.to_string());
+            unix_listeners.push((name, unix_listener));
+        }
 
-            if i > 60 {
-                cli::unrecoverable_clap_error_with_cmd("Timout exceeded! Server failed to start.");
+        for (name, unix_listener) in unix_listeners {
+            let unix_listener = match unix_listener {
+                Ok(unix_listener) => unix_listener,
+                Err(err) => return cli::unrecoverable_clap_error_with_cmd(err),
+            };
+            names.push(name);
+            tasks.push(tokio::spawn(listener::serve_unix(
+                unix_listener,
+                app.clone(),
+                args,
+            )));
+        }
+    }
+    // The admin listener only serves the metrics
+    if let Some(addr) = args.metrics_addr {
+        let tcp_listener = match listener::bind(addr, &listeners) {
+            Ok(tcp_listener) => tcp_listener,
+            Err(err) => return cli::unrecoverable_clap_error_with_cmd(err),
+        };
+        let admin = listener::Listener {
+            addr,
+            protocol: config::Protocol::Auto,
+            tls: None,
+            http3: None,
+        };
+        names.push(format!("{}/metrics", admin));
+        tasks.push(tokio::spawn(listener::serve(
+            tcp_listener,
+            admin,
+            Router::new().route("/metrics", get(metrics::handler)),
+            args,
+        )));
+    }
+    // The redirecting listener only serves ACME challenges itself
+    if let Some(addr) = args.redirect_http {
+        let https_port = listeners
+            .iter()
+            .find(|listener| listener.tls.is_some())
+            .map_or(443, |listener| listener.addr.port());
+        let tcp_listener = match listener::bind(addr, &listeners) {
+            Ok(tcp_listener) => tcp_listener,
+            Err(err) => return cli::unrecoverable_clap_error_with_cmd(err),
+        };
+        let redirecting = listener::Listener {
+            addr,
+            protocol: config::Protocol::Auto,
+            tls: None,
+            http3: None,
+        };
+        names.push(format!("{} (redirecting to HTTPS)", redirecting));
+        let app = app.clone().layer(middleware::from_fn(move |req, next| {
+            https::redirect(req, next, https_port, args)
+        }));
+        tasks.push(tokio::spawn(listener::serve(
+            tcp_listener,
+            redirecting,
+            app,
+            args,
+        )));
+    }
+    let url = listeners.first().map(|listener| listener.to_string());
+    let hsts = https::hsts(args);
+    let mut endpoints = vec![];
+    for listener in &listeners {
+        if let Some(config) = &listener.http3 {
+            match http3::bind(listener.addr, config.clone(), &listeners) {
+                Ok(endpoint) => endpoints.push(Some(endpoint)),
+                Err(err) => return cli::unrecoverable_clap_error_with_cmd(err),
             }
+            names.push(format!("{} (HTTP/3)", listener));
+        } else {
+            endpoints.push(None);
+        }
+    }
+    for ((tcp_listener, listener), endpoint) in bound.into_iter().zip(listeners).zip(endpoints) {
+        let mut app = match (&listener.tls, &hsts) {
+            (Some(_), Some(hsts)) => {
+                let hsts = hsts.clone();
+                app.clone().layer(middleware::from_fn(move |req, next| {
+                    https::set_hsts(req, next, hsts.clone())
+                }))
+            }
+            _ => app.clone(),
+        };
+        // HTTP/3 is advertised to the clients of the TCP listener
+        if let Some(endpoint) = endpoint {
+            tasks.push(tokio::spawn(http3::serve(endpoint, app.clone(), args)));
+            let alt_svc = http3::alt_svc(listener.addr.port());
+            app = app.layer(middleware::from_fn(move |req, next| {
+                http3::set_alt_svc(req, next, alt_svc.clone())
+            }));
+        }
+        tasks.push(tokio::spawn(listener::serve(
+            tcp_listener,
+            listener,
+            app,
+            args,
+        )));
+    }
 
-            i += 1;
+    // Certificates are ordered once the listeners can answer the challenges
+    if let Some(acme) = &args.config.acme {
+        if let Err(err) = acme::init(acme) {
+            return cli::unrecoverable_clap_error_with_cmd(err);
         }
-    });
+    }
 
-    addr_is_available_or_exit(args.addr).await;
-    let server = axum::Server::bind(&args.addr)
-        .serve(app.into_make_service())
-        .await;
-    if let Err(err) = server {
-        cli::unrecoverable_clap_error_with_cmd(err);
+    println!("Server has started. Listening on {}.", names.join(", "));
+    #[cfg(unix)]
+    {
+        systemd::notify("READY=1");
+        systemd::spawn_watchdog();
+        systemd::spawn_shutdown_handler();
+    }
+    if let (true, Some(url)) = (args.open, url) {
+        open::that(url).unwrap_or_else(|err| {
+            println!("Failed to open the browser. Reason: {}", err);
+        });
+    }
+
+    for task in tasks {
+        if let Err(err) = task.await {
+            cli::unrecoverable_clap_error_with_cmd(err);
+        }
     }
 }
@@ -0,0 +1,397 @@
+use crate::{
+    access::{self, Access},
+    cli,
+    mounts::Mount,
+    timefmt,
+};
+use axum::http::Uri;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+use std::{fs, path::Path, time::SystemTime};
+
+// Nested includes deeper than Apache's limit are refused, which catches include loops as well
+const MAX_DEPTH: usize = 16;
+const DEFAULT_ERRMSG: &str = "[an error occurred while processing this directive]";
+const DEFAULT_TIMEFMT: &str = "%A, %d-%b-%Y %H:%M:%S %Z";
+const START: &[u8] = b"<!--#";
+const END: &[u8] = b"-->";
+
+pub fn is_ssi(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("shtml"))
+}
+
+struct Context<'a> {
+    mount: &'a Mount,
+    uri: &'a Uri,
+    args: &'a cli::Args,
+    // The file of the requested document, relative to the mount
+    document: &'a str,
+    errmsg: String,
+    timefmt: String,
+    abbrev_sizes: bool,
+}
+
+// Only these variables can be echoed, nothing of the environment or the request headers
+const VARIABLES: [&str; 6] = [
+    "DOCUMENT_NAME",
+    "DOCUMENT_URI",
+    "QUERY_STRING",
+    "DATE_GMT",
+    "DATE_LOCAL",
+    "LAST_MODIFIED",
+];
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+// Splits `include virtual="/header.html"` into the directive and its attributes
+fn parse_directive(tag: &str) -> Option<(&str, Vec<(&str, &str)>)> {
+    let tag = tag.trim();
+    let name_end = tag.find(char::is_whitespace).unwrap_or(tag.len());
+    let (name, mut rest) = tag.split_at(name_end);
+
+    let mut attributes = vec![];
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            break;
+        }
+        let (attribute, value) = rest.split_once('=')?;
+        let value = value.trim_start();
+        let quote = value.chars().next()?;
+        let (value, after) = if quote == '"' || quote == '\'' {
+            value[1..].split_once(quote)?
+        } else {
+            value.split_at(value.find(char::is_whitespace).unwrap_or(value.len()))
+        };
+        attributes.push((attribute.trim(), value));
+        rest = after;
+    }
+
+    Some((name, attributes))
+}
+
+// Joins the path of a directive with the directory of the document, refusing anything outside of
+// the mount
+fn join(dir: &str, path: &str) -> Option<String> {
+    let mut segments: Vec<&str> = if path.starts_with('/') {
+        vec![]
+    } else {
+        dir.split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect()
+    };
+    for segment in path.split(['/', '\\']) {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    Some(segments.join("/"))
+}
+
+fn fsize(size: u64, abbrev: bool) -> String {
+    if !abbrev {
+        // Grouped in thousands like Apache
+        let digits = size.to_string();
+        let mut out = String::new();
+        for (i, c) in digits.chars().enumerate() {
+            if i > 0 && i % 3 == digits.len() % 3 {
+                out.push(',');
+            }
+            out.push(c);
+        }
+        return out;
+    }
+    if size < 1024 {
+        return size.to_string();
+    }
+
+    let mut value = size as f64 / 1024.0;
+    let mut units = ["K", "M", "G", "T"].iter();
+    let mut unit = units.next().unwrap();
+    while value >= 1024.0 {
+        match units.next() {
+            Some(next) => {
+                value /= 1024.0;
+                unit = next;
+            }
+            None => break,
+        }
+    }
+    if value < 10.0 {
+        format!("{:.1}{}", value, unit)
+    } else {
+        format!("{:.0}{}", value, unit)
+    }
+}
+
+impl<'a> Context<'a> {
+    // Resolves the `file` or `virtual` attribute of a directive to a file of the mount. `file` is
+    // relative to the document and can't go up, `virtual` is a URL path.
+    fn resolve(&self, dir: &str, attribute: &str, value: &str) -> Option<String> {
+        let path = match attribute {
+            "file" => {
+                if value.starts_with('/') || value.split(['/', '\\']).any(|segment| segment == "..")
+                {
+                    return None;
+                }
+                join(dir, value)?
+            }
+            "virtual" => {
+                let value = value.split('?').next().unwrap_or_default();
+                let value = percent_decode_str(value).decode_utf8().ok()?;
+                let value = match value.strip_prefix('/') {
+                    Some(_) => {
+                        let rest = value.strip_prefix(self.mount.prefix.as_str())?;
+                        if !rest.is_empty() && !rest.starts_with('/') {
+                            return None;
+                        }
+                        format!("/{}", rest.trim_start_matches('/'))
+                    }
+                    None => value.into_owned(),
+                };
+                join(dir, &value)?
+            }
+            _ => return None,
+        };
+
+        let allowed = self.mount.path.join(&path).is_file()
+            && access::check(self.mount, &path, false, self.args) == Access::Allowed;
+        allowed.then_some(path)
+    }
+
+    // The file named by the only `file` or `virtual` attribute of a directive
+    fn target(&self, dir: &str, attributes: &[(&str, &str)]) -> Option<String> {
+        match attributes {
+            [(attribute, value)] => self.resolve(dir, attribute, value),
+            _ => None,
+        }
+    }
+
+    fn format_time(&self, time: SystemTime) -> String {
+        timefmt::strftime(&self.timefmt, time)
+    }
+
+    fn modified(&self, path: &str) -> Option<SystemTime> {
+        fs::metadata(self.mount.path.join(path))
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+
+    fn variable(&self, name: &str) -> Option<String> {
+        match name {
+            "DOCUMENT_NAME" => self.document.rsplit('/').next().map(String::from),
+            "DOCUMENT_URI" => Some(String::from(self.uri.path())),
+            "QUERY_STRING" => Some(String::from(self.uri.query().unwrap_or_default())),
+            // Times are always written in UTC
+            "DATE_GMT" | "DATE_LOCAL" => Some(self.format_time(SystemTime::now())),
+            "LAST_MODIFIED" => self
+                .modified(self.document)
+                .map(|time| self.format_time(time)),
+            _ => None,
+        }
+    }
+
+    fn directive(&mut self, tag: &str, path: &str, depth: usize, out: &mut Vec<u8>) -> bool {
+        let (name, attributes) = match parse_directive(tag) {
+            Some(directive) => directive,
+            None => return false,
+        };
+        let dir = path.rsplit_once('/').map_or("", |(dir, _)| dir);
+
+        match name {
+            "include" => {
+                let include = match self.target(dir, &attributes) {
+                    Some(include) => include,
+                    None => return false,
+                };
+                let contents = match fs::read(self.mount.path.join(&include)) {
+                    Ok(contents) => contents,
+                    Err(_) => return false,
+                };
+                if !is_ssi(&include) {
+                    out.extend_from_slice(&contents);
+                } else if depth < MAX_DEPTH {
+                    self.process(&contents, &include, depth + 1, out);
+                } else {
+                    return false;
+                }
+            }
+            "echo" => {
+                let mut var = None;
+                let mut encoding = "entity";
+                for (attribute, value) in attributes {
+                    match attribute {
+                        "var" => var = Some(value),
+                        "encoding" => encoding = value,
+                        _ => return false,
+                    }
+                }
+                let var = match var {
+                    Some(var) if VARIABLES.contains(&var) => var,
+                    _ => return false,
+                };
+                let value = self.variable(var).unwrap_or_else(|| String::from("(none)"));
+                let value = match encoding {
+                    "none" => value,
+                    "url" => utf8_percent_encode(&value, NON_ALPHANUMERIC).to_string(),
+                    "entity" => escape_html(&value),
+                    _ => return false,
+                };
+                out.extend_from_slice(value.as_bytes());
+            }
+            "config" => {
+                for (attribute, value) in attributes {
+                    match (attribute, value) {
+                        ("timefmt", value) => self.timefmt = String::from(value),
+                        ("errmsg", value) => self.errmsg = String::from(value),
+                        ("sizefmt", "abbrev") => self.abbrev_sizes = true,
+                        ("sizefmt", "bytes") => self.abbrev_sizes = false,
+                        _ => return false,
+                    }
+                }
+            }
+            "flastmod" => {
+                let modified = match self
+                    .target(dir, &attributes)
+                    .and_then(|target| self.modified(&target))
+                {
+                    Some(modified) => modified,
+                    None => return false,
+                };
+                out.extend_from_slice(self.format_time(modified).as_bytes());
+            }
+            "fsize" => {
+                let size = match self
+                    .target(dir, &attributes)
+                    .and_then(|target| fs::metadata(self.mount.path.join(target)).ok())
+                {
+                    Some(metadata) => metadata.len(),
+                    None => return false,
+                };
+                out.extend_from_slice(fsize(size, self.abbrev_sizes).as_bytes());
+            }
+            _ => return false,
+        }
+
+        true
+    }
+
+    fn process(&mut self, contents: &[u8], path: &str, depth: usize, out: &mut Vec<u8>) {
+        let mut rest = contents;
+        while let Some(start) = find(rest, START) {
+            let end = match find(&rest[start..], END) {
+                Some(end) => start + end,
+                None => break,
+            };
+            out.extend_from_slice(&rest[..start]);
+
+            let tag = String::from_utf8_lossy(&rest[start + START.len()..end]);
+            if !self.directive(&tag, path, depth, out) {
+                out.extend_from_slice(self.errmsg.as_bytes());
+            }
+            rest = &rest[end + END.len()..];
+        }
+        out.extend_from_slice(rest);
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+// Processes the directives of a `.shtml` document. Included `.shtml` files are processed as well,
+// other files are included as they are.
+pub fn render(
+    contents: &[u8],
+    document: &str,
+    mount: &Mount,
+    uri: &Uri,
+    args: &cli::Args,
+) -> Vec<u8> {
+    let mut context = Context {
+        mount,
+        uri,
+        args,
+        document,
+        errmsg: String::from(DEFAULT_ERRMSG),
+        timefmt: String::from(DEFAULT_TIMEFMT),
+        abbrev_sizes: true,
+    };
+    let mut out = Vec::with_capacity(contents.len());
+    context.process(contents, document, 0, &mut out);
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn join_paths() {
+        assert_eq!(
+            join("docs", "footer.html").as_deref(),
+            Some("docs/footer.html")
+        );
+        assert_eq!(
+            join("docs/a", "../b/./c.html").as_deref(),
+            Some("docs/b/c.html")
+        );
+        assert_eq!(join("docs", "/header.html").as_deref(), Some("header.html"));
+        assert_eq!(join("docs", "a\\b.html").as_deref(), Some("docs/a/b.html"));
+        assert_eq!(join("", "../secret").as_deref(), None);
+        assert_eq!(join("docs", "../../secret").as_deref(), None);
+        assert_eq!(join("docs", "/../secret").as_deref(), None);
+    }
+
+    #[test]
+    fn parse_directives() {
+        let (name, attributes) = parse_directive(" include virtual=\"/a b.html\" ").unwrap();
+        assert_eq!(name, "include");
+        assert_eq!(attributes, vec![("virtual", "/a b.html")]);
+
+        let (name, attributes) =
+            parse_directive("config timefmt='%Y' sizefmt=bytes errmsg=\"\"").unwrap();
+        assert_eq!(name, "config");
+        assert_eq!(
+            attributes,
+            vec![("timefmt", "%Y"), ("sizefmt", "bytes"), ("errmsg", "")]
+        );
+
+        assert!(parse_directive("echo var").is_none());
+        assert!(parse_directive("echo var=\"unclosed").is_none());
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(fsize(999, false), "999");
+        assert_eq!(fsize(1234567, false), "1,234,567");
+        assert_eq!(fsize(500, true), "500");
+        assert_eq!(fsize(1536, true), "1.5K");
+        assert_eq!(fsize(20 * 1024, true), "20K");
+        assert_eq!(fsize(3 * 1024 * 1024 * 1024, true), "3.0G");
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(
+            escape_html("<a href=\"x\">'&'</a>"),
+            "&lt;a href=&quot;x&quot;&gt;&#39;&amp;&#39;&lt;/a&gt;"
+        );
+    }
+}
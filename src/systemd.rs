@@ -0,0 +1,292 @@
+use socket2::{Domain, Socket, Type};
+use std::{
+    env,
+    ffi::OsStr,
+    net::{SocketAddr, TcpListener},
+    os::unix::{
+        io::{FromRawFd, IntoRawFd, RawFd},
+        net::{UnixDatagram, UnixListener},
+    },
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    time as tokio_time,
+};
+
+// The first file descriptor passed by systemd, see sd_listen_fds(3)
+const LISTEN_FDS_START: RawFd = 3;
+// The passed sockets are only taken over once
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+// Listening sockets passed by systemd socket activation
+#[derive(Default)]
+pub struct Activated {
+    pub tcp: Vec<(SocketAddr, TcpListener)>,
+    pub unix: Vec<(Option<PathBuf>, UnixListener)>,
+}
+
+impl Activated {
+    pub fn take_tcp(&mut self, addr: SocketAddr) -> Option<TcpListener> {
+        let i = self.tcp.iter().position(|(other, _)| *other == addr)?;
+
+        Some(self.tcp.remove(i).1)
+    }
+
+    pub fn take_unix(&mut self, path: &Path) -> Option<UnixListener> {
+        let i = self
+            .unix
+            .iter()
+            .position(|(other, _)| other.as_deref() == Some(path))?;
+
+        Some(self.unix.remove(i).1)
+    }
+}
+
+fn listen_fds() -> Option<RawFd> {
+    parse_listen_fds(
+        &env::var("LISTEN_PID").ok()?,
+        &env::var("LISTEN_FDS").ok()?,
+        process::id(),
+    )
+}
+
+// The sockets are only meant for this process, not for the ones it spawns
+fn parse_listen_fds(pid: &str, fds: &str, own_pid: u32) -> Option<RawFd> {
+    let pid: u32 = pid.parse().ok()?;
+    let fds: RawFd = fds.parse().ok()?;
+
+    (pid == own_pid && fds > 0).then_some(fds)
+}
+
+pub fn is_socket_activated() -> bool {
+    listen_fds().is_some()
+}
+
+// Takes the passed sockets over. Anything but stream sockets is ignored.
+pub fn listeners() -> Activated {
+    let mut activated = Activated::default();
+    let fds = match listen_fds() {
+        Some(fds) if !TAKEN.swap(true, Ordering::SeqCst) => fds,
+        _ => return activated,
+    };
+    // The variables are left set, changing the environment isn't sound once the runtime has
+    // started its threads. Spawned processes ignore them since `LISTEN_PID` isn't theirs.
+
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + fds {
+        // SAFETY: systemd passes `LISTEN_FDS` open descriptors starting at `LISTEN_FDS_START`, and
+        // `LISTEN_PID` says they're this process' own. Nothing else opens or closes them and
+        // `TAKEN` makes sure each one is only wrapped once, so the socket owns it.
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        let addr = match (socket.r#type(), socket.local_addr()) {
+            (Ok(Type::STREAM), Ok(addr)) => addr,
+            // Left open since it isn't ours to close
+            _ => {
+                let _ = socket.into_raw_fd();
+                continue;
+            }
+        };
+        // systemd passes the sockets in blocking mode
+        if socket.set_nonblocking(true).is_err() {
+            let _ = socket.into_raw_fd();
+            continue;
+        }
+
+        if let Some(addr) = addr.as_socket() {
+            activated.tcp.push((addr, socket.into()));
+            continue;
+        }
+        if Domain::from(i32::from(addr.family())) != Domain::UNIX {
+            let _ = socket.into_raw_fd();
+            continue;
+        }
+        // SAFETY: The socket owned the descriptor and releases it here, and it's a valid Unix
+        // stream socket as checked above.
+        let unix_listener = unsafe { UnixListener::from_raw_fd(socket.into_raw_fd()) };
+        let path = unix_listener
+            .local_addr()
+            .ok()
+            .and_then(|addr| addr.as_pathname().map(PathBuf::from));
+        activated.unix.push((path, unix_listener));
+    }
+
+    activated
+}
+
+// Sends a state change to systemd, see sd_notify(3). Does nothing when not started by systemd.
+pub fn notify(state: &str) {
+    if let Some(path) = env::var_os("NOTIFY_SOCKET") {
+        send_state(&path, state);
+    }
+}
+
+fn send_state(path: &OsStr, state: &str) {
+    let socket = match UnixDatagram::unbound() {
+        Ok(socket) => socket,
+        Err(_) => return,
+    };
+
+    // Abstract socket names start with '@'
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::{
+            linux::net::SocketAddrExt, unix::ffi::OsStrExt, unix::net::SocketAddr as UnixSocketAddr,
+        };
+
+        if let Some(name) = path.as_bytes().strip_prefix(b"@") {
+            if let Ok(addr) = UnixSocketAddr::from_abstract_name(name) {
+                let _ = socket.send_to_addr(state.as_bytes(), &addr);
+            }
+            return;
+        }
+    }
+    let _ = socket.send_to(state.as_bytes(), Path::new(path));
+}
+
+// Pings the watchdog twice per interval when `WatchdogSec` is set
+pub fn spawn_watchdog() {
+    if let Some(pid) = env::var("WATCHDOG_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+    {
+        if pid != process::id() {
+            return;
+        }
+    }
+    let usec = match env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse::<u64>().ok())
+    {
+        Some(usec) if usec > 0 => usec,
+        _ => return,
+    };
+
+    tokio::spawn(async move {
+        let mut interval = tokio_time::interval(Duration::from_micros(usec / 2));
+        loop {
+            interval.tick().await;
+            notify("WATCHDOG=1");
+        }
+    });
+}
+
+// Tells systemd the server is stopping before exiting on SIGTERM or SIGINT
+pub fn spawn_shutdown_handler() {
+    if env::var_os("NOTIFY_SOCKET").is_none() {
+        return;
+    }
+    let (mut terminate, mut interrupt) = match (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) {
+        (Ok(terminate), Ok(interrupt)) => (terminate, interrupt),
+        _ => return,
+    };
+
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = interrupt.recv() => {}
+        }
+
+        notify("STOPPING=1");
+        process::exit(0);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("sfhs-systemd-{}-{}", process::id(), name))
+    }
+
+    #[test]
+    fn listen_fds_of_this_process() {
+        assert_eq!(parse_listen_fds("42", "2", 42), Some(2));
+    }
+
+    #[test]
+    fn listen_fds_of_another_process() {
+        assert_eq!(parse_listen_fds("41", "2", 42), None);
+    }
+
+    #[test]
+    fn listen_fds_zero() {
+        assert_eq!(parse_listen_fds("42", "0", 42), None);
+        assert_eq!(parse_listen_fds("42", "-1", 42), None);
+    }
+
+    #[test]
+    fn listen_fds_not_numeric() {
+        assert_eq!(parse_listen_fds("self", "2", 42), None);
+        assert_eq!(parse_listen_fds("42", "two", 42), None);
+        assert_eq!(parse_listen_fds("", "", 42), None);
+    }
+
+    #[test]
+    fn take_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut activated = Activated::default();
+        activated.tcp.push((addr, listener));
+
+        assert!(activated
+            .take_tcp(SocketAddr::from(([127, 0, 0, 1], 1)))
+            .is_none());
+        assert!(activated.take_tcp(addr).is_some());
+        assert!(activated.take_tcp(addr).is_none());
+    }
+
+    #[test]
+    fn take_unix() {
+        let path = temp_path("take.sock");
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let mut activated = Activated::default();
+        activated
+            .unix
+            .push((None, UnixListener::bind(temp_path("unnamed.sock")).unwrap()));
+        activated.unix.push((Some(path.clone()), listener));
+
+        assert!(activated.take_unix(&temp_path("other.sock")).is_none());
+        assert!(activated.take_unix(&path).is_some());
+        assert!(activated.take_unix(&path).is_none());
+        assert_eq!(activated.unix.len(), 1);
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(temp_path("unnamed.sock"));
+    }
+
+    #[test]
+    fn send_state_to_path() {
+        let path = temp_path("notify.sock");
+        let _ = fs::remove_file(&path);
+        let receiver = UnixDatagram::bind(&path).unwrap();
+
+        send_state(path.as_os_str(), "READY=1");
+        let mut buf = [0; 64];
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+        let _ = fs::remove_file(&path);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn send_state_to_abstract_name() {
+        use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr as UnixSocketAddr};
+
+        let name = format!("sfhs-systemd-{}-notify", process::id());
+        let addr = UnixSocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let receiver = UnixDatagram::bind_addr(&addr).unwrap();
+
+        send_state(OsStr::new(&format!("@{}", name)), "STOPPING=1");
+        let mut buf = [0; 64];
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"STOPPING=1");
+    }
+}
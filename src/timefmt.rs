@@ -0,0 +1,156 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DAYS: [&str; 7] = [
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+];
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+// Howard Hinnant's `civil_from_days`, returning the year, the month and the day
+fn civil_from_days(days: i64) -> (i64, usize, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+
+    (
+        year_of_era + era * 400 + i64::from(month <= 2),
+        month as usize,
+        day,
+    )
+}
+
+// Formats the time in UTC like `strftime`. Unknown conversions are kept as they are.
+pub fn strftime(format: &str, time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs()) as i64;
+    let (days, secs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    let (year, month, day) = civil_from_days(days);
+    let (hour, minute, second) = (secs / 3600, secs / 60 % 60, secs % 60);
+    // 1970-01-01 was a Thursday
+    let weekday = (days + 4).rem_euclid(7) as usize;
+    let is_leap_year = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let day_of_year = day
+        + (1..month)
+            .map(|month| match month {
+                2 if is_leap_year => 29,
+                2 => 28,
+                4 | 6 | 9 | 11 => 30,
+                _ => 31,
+            })
+            .sum::<i64>();
+    let hour_12 = match hour % 12 {
+        0 => 12,
+        hour => hour,
+    };
+
+    let mut out = String::with_capacity(format.len() * 2);
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+
+        let conversion = match chars.next() {
+            Some(conversion) => conversion,
+            None => {
+                out.push('%');
+                break;
+            }
+        };
+        let formatted = match conversion {
+            'a' => String::from(&DAYS[weekday][..3]),
+            'A' => String::from(DAYS[weekday]),
+            'b' | 'h' => String::from(&MONTHS[month - 1][..3]),
+            'B' => String::from(MONTHS[month - 1]),
+            'c' => strftime("%a %b %e %H:%M:%S %Y", time),
+            'd' => format!("{:02}", day),
+            'D' => strftime("%m/%d/%y", time),
+            'e' => format!("{:2}", day),
+            'F' => strftime("%Y-%m-%d", time),
+            'H' => format!("{:02}", hour),
+            'I' => format!("{:02}", hour_12),
+            'j' => format!("{:03}", day_of_year),
+            'm' => format!("{:02}", month),
+            'M' => format!("{:02}", minute),
+            'p' => String::from(if hour < 12 { "AM" } else { "PM" }),
+            'S' => format!("{:02}", second),
+            's' => (days * 86400 + secs).to_string(),
+            'T' => strftime("%H:%M:%S", time),
+            'y' => format!("{:02}", year % 100),
+            'Y' => year.to_string(),
+            'z' => String::from("+0000"),
+            'Z' => String::from("GMT"),
+            '%' => String::from("%"),
+            other => format!("%{}", other),
+        };
+        out.push_str(&formatted);
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn epoch() {
+        assert_eq!(
+            strftime("%a %A %d %b %B %Y %H:%M:%S %j %z %Z", at(0)),
+            "Thu Thursday 01 Jan January 1970 00:00:00 001 +0000 GMT"
+        );
+    }
+
+    #[test]
+    fn leap_day() {
+        // 2000-02-29 13:05:09
+        let time = at(951829509);
+        assert_eq!(strftime("%F %T", time), "2000-02-29 13:05:09");
+        assert_eq!(
+            strftime("%j %I %p %y %e %D", time),
+            "060 01 PM 00 29 02/29/00"
+        );
+        assert_eq!(strftime("%j", at(951829509 + 306 * 86400)), "366");
+    }
+
+    #[test]
+    fn composite_and_unknown_conversions() {
+        let time = at(1700000000);
+        assert_eq!(strftime("%c", time), "Tue Nov 14 22:13:20 2023");
+        assert_eq!(strftime("%s", time), "1700000000");
+        assert_eq!(strftime("100%% %q %", time), "100% %q %");
+        assert_eq!(strftime("%I %p", at(43200)), "12 PM");
+        assert_eq!(strftime("%I %p", at(0)), "12 AM");
+    }
+}
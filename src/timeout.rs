@@ -0,0 +1,122 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{self as tokio_time, Instant, Sleep},
+};
+
+// Closes connections that make no progress. The idle timer restarts on every read or write, and
+// the write timer runs while the client doesn't accept the response.
+pub struct TimeoutStream<S> {
+    stream: S,
+    idle_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    idle: Pin<Box<Sleep>>,
+    write: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> TimeoutStream<S> {
+    pub fn new(stream: S, idle_timeout: Option<Duration>, write_timeout: Option<Duration>) -> Self {
+        // Without an idle timeout the timer is never polled
+        let idle = tokio_time::sleep(idle_timeout.unwrap_or_default());
+
+        Self {
+            stream,
+            idle_timeout,
+            write_timeout,
+            idle: Box::pin(idle),
+            write: None,
+        }
+    }
+
+    fn reset_idle(&mut self) {
+        if let Some(timeout) = self.idle_timeout {
+            self.idle.as_mut().reset(Instant::now() + timeout);
+        }
+    }
+
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<io::Error> {
+        if self.idle_timeout.is_none() {
+            return Poll::Pending;
+        }
+
+        self.idle
+            .as_mut()
+            .poll(cx)
+            .map(|_| io::Error::new(io::ErrorKind::TimedOut, "The connection was idle"))
+    }
+
+    // Tracks a write and fails it once the client has kept it waiting for too long
+    fn poll_write_timeout<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        poll: Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        if poll.is_ready() {
+            self.write = None;
+            self.reset_idle();
+            return poll;
+        }
+        let timeout = match self.write_timeout {
+            Some(timeout) => timeout,
+            None => return poll,
+        };
+
+        let write = self
+            .write
+            .get_or_insert_with(|| Box::pin(tokio_time::sleep(timeout)));
+        match write.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "The client stopped receiving",
+            ))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TimeoutStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.stream).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                this.reset_idle();
+                Poll::Ready(result)
+            }
+            Poll::Pending => this.poll_idle(cx).map(Err),
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TimeoutStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.stream).poll_write(cx, buf);
+
+        this.poll_write_timeout(cx, poll)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.stream).poll_flush(cx);
+
+        this.poll_write_timeout(cx, poll)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
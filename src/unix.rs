@@ -0,0 +1,82 @@
+use std::{
+    fs::{self, DirBuilder, Permissions},
+    os::unix::{
+        fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+        net::UnixStream as StdUnixStream,
+    },
+    path::Path,
+    process,
+};
+use tokio::net::UnixListener;
+
+// Removes a socket file left behind by a previous run. Sockets that still accept connections are
+// in use by another process and kept.
+fn remove_stale_socket(path: &Path) -> Result<(), String> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return Ok(()),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(format!(
+            "Unable to bind on: {}! Reason: The file exists and is not a socket.",
+            path.display()
+        ));
+    }
+    if StdUnixStream::connect(path).is_ok() {
+        return Err(format!(
+            "Unable to bind on: {}! Reason: The socket is in use by another process.",
+            path.display()
+        ));
+    }
+
+    fs::remove_file(path).map_err(|err| {
+        format!(
+            "Unable to remove the stale socket: {}! Reason: {}.",
+            path.display(),
+            err
+        )
+    })
+}
+
+// With a mode, the socket is bound in a private directory next to it and only moved in place once
+// it has its mode, so nobody can connect while it still has the permissions of the umask
+pub fn bind(path: &Path, mode: Option<u32>) -> Result<UnixListener, String> {
+    remove_stale_socket(path)?;
+    let mode = match mode {
+        Some(mode) => mode,
+        None => {
+            return UnixListener::bind(path)
+                .map_err(|err| format!("Unable to bind on: {}! Reason: {}.", path.display(), err))
+        }
+    };
+
+    let dir = path.with_file_name(format!(".sfhs-{}", process::id()));
+    DirBuilder::new().mode(0o700).create(&dir).map_err(|err| {
+        format!(
+            "Unable to create a directory next to: {}! Reason: {}.",
+            path.display(),
+            err
+        )
+    })?;
+    let private_path = dir.join(path.file_name().unwrap_or_default());
+    let listener = UnixListener::bind(&private_path)
+        .map_err(|err| format!("Unable to bind on: {}! Reason: {}.", path.display(), err))
+        .and_then(|listener| {
+            fs::set_permissions(&private_path, Permissions::from_mode(mode)).map_err(|err| {
+                format!(
+                    "Unable to set the permissions of: {}! Reason: {}.",
+                    path.display(),
+                    err
+                )
+            })?;
+            fs::rename(&private_path, path).map_err(|err| {
+                format!("Unable to bind on: {}! Reason: {}.", path.display(), err)
+            })?;
+
+            Ok(listener)
+        });
+    let _ = fs::remove_file(&private_path);
+    let _ = fs::remove_dir(&dir);
+
+    listener
+}
@@ -0,0 +1,468 @@
+use crate::{
+    access,
+    cli::{self, Overwrite},
+    hosts,
+    mounts::{self, Mount},
+    redirects,
+    server::{self, SetHeader},
+};
+use axum::{
+    body::{Body, HttpBody},
+    extract::{multipart::MultipartRejection, Multipart},
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tokio::{fs, io::AsyncWriteExt};
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+static TEMP_FILES: AtomicU64 = AtomicU64::new(0);
+
+fn base64(input: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in input.chunks(3) {
+        let bytes = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = u32::from(bytes[0]) << 16 | u32::from(bytes[1]) << 8 | u32::from(bytes[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - i * 6)) as usize & 63] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+
+    out
+}
+
+// Compares every byte so the time taken doesn't tell how much of the credentials matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn authorized(headers: &HeaderMap, args: &cli::Args) -> bool {
+    let credentials = match &args.upload_auth {
+        Some(credentials) => credentials,
+        None => return false,
+    };
+    let expected = format!("Basic {}", base64(credentials.as_bytes()));
+
+    headers
+        .get(header::AUTHORIZATION)
+        .map(|value| constant_time_eq(value.as_bytes(), expected.as_bytes()))
+        .unwrap_or(false)
+}
+
+async fn error(status: StatusCode, headers: &HeaderMap, uri: &Uri, args: &cli::Args) -> Response {
+    let res = server::error_response(status, headers, uri, args).await;
+    if status != StatusCode::UNAUTHORIZED {
+        return res.into_response();
+    }
+
+    (
+        SetHeader(
+            header::WWW_AUTHENTICATE.to_string(),
+            String::from("Basic realm=\"static-file-http-server\""),
+        ),
+        res,
+    )
+        .into_response()
+}
+
+// The mount a request path belongs to and the path inside of it
+fn target<'a>(headers: &HeaderMap, uri: &Uri, args: &'a cli::Args) -> Option<(&'a Mount, String)> {
+    let host = hosts::find(&args.hosts, hosts::host_name(headers, uri).as_deref());
+    let path = server::relative_path(server::strip_base_path(uri.path(), args)?)?;
+    let (mount, path) = mounts::find(&host.mounts, &path);
+    if mount.prefix.is_empty() && path == redirects::REDIRECTS_FILE {
+        return None;
+    }
+
+    Some((mount, path))
+}
+
+fn location(mount: &Mount, path: &str, args: &cli::Args) -> String {
+    format!(
+        "{}{}/{}",
+        args.base_path.as_deref().unwrap_or(""),
+        mount.prefix,
+        server::encode_path(path)
+    )
+}
+
+fn io_status(kind: ErrorKind) -> StatusCode {
+    match kind {
+        ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+        ErrorKind::AlreadyExists => StatusCode::CONFLICT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+// Whether a file is in the way of a directory on the path. `ErrorKind::NotADirectory` would need
+// Rust 1.83.
+async fn file_in_the_way(path: &Path) -> bool {
+    for ancestor in path.ancestors().skip(1) {
+        if let Ok(metadata) = fs::metadata(ancestor).await {
+            return !metadata.is_dir();
+        }
+    }
+
+    false
+}
+
+// Creates the missing directories up to `dir`. Returns the created ones, deepest last, so they can
+// be removed again when the upload fails.
+async fn create_dirs(dir: &Path) -> Result<Vec<PathBuf>, StatusCode> {
+    let mut missing = vec![];
+    for ancestor in dir.ancestors() {
+        match fs::metadata(ancestor).await {
+            Ok(metadata) if metadata.is_dir() => break,
+            Ok(_) => return Err(StatusCode::CONFLICT),
+            Err(_) => missing.push(ancestor.to_path_buf()),
+        }
+    }
+
+    let mut created = vec![];
+    for dir in missing.into_iter().rev() {
+        match fs::create_dir(&dir).await {
+            Ok(()) => created.push(dir),
+            // By a concurrent upload
+            Err(err) if err.kind() == ErrorKind::AlreadyExists && dir.is_dir() => {}
+            Err(err) => {
+                remove_dirs(&created);
+                return Err(io_status(err.kind()));
+            }
+        }
+    }
+
+    Ok(created)
+}
+
+// Directories that got files from other uploads in the meantime aren't empty and are kept
+fn remove_dirs(dirs: &[PathBuf]) {
+    for dir in dirs.iter().rev() {
+        let _ = std::fs::remove_dir(dir);
+    }
+}
+
+// `report.txt` becomes `report-1.txt`
+fn numbered(name: &str, i: u32) -> String {
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => format!("{}-{}.{}", stem, i, ext),
+        _ => format!("{}-{}", name, i),
+    }
+}
+
+// Browsers may send full paths as the file name, only the last segment is used
+fn file_name(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next()?;
+    if name.is_empty() || name == "." || name == ".." {
+        return None;
+    }
+
+    Some(String::from(name))
+}
+
+// Uploads are written next to their destination and moved in place once complete, so partial
+// uploads are never served. The directories created for them only stay when they succeed.
+struct TempUpload {
+    path: PathBuf,
+    file: fs::File,
+    size: u64,
+    limit: u64,
+    created_dirs: Vec<PathBuf>,
+}
+
+impl TempUpload {
+    async fn create(dir: &Path, limit: u64) -> Result<Self, StatusCode> {
+        let created_dirs = create_dirs(dir).await?;
+        let path = dir.join(format!(
+            ".sfhs-upload-{}-{}",
+            process::id(),
+            TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await
+        {
+            Ok(file) => file,
+            Err(err) => {
+                remove_dirs(&created_dirs);
+                return Err(io_status(err.kind()));
+            }
+        };
+
+        Ok(Self {
+            path,
+            file,
+            size: 0,
+            limit,
+            created_dirs,
+        })
+    }
+
+    async fn write(&mut self, chunk: &[u8]) -> Result<(), StatusCode> {
+        self.size += chunk.len() as u64;
+        if self.size > self.limit {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+
+        self.file
+            .write_all(chunk)
+            .await
+            .map_err(|err| io_status(err.kind()))
+    }
+
+    // Returns the name the file was stored under and whether an existing file was replaced.
+    // Hard links fail instead of replacing existing files, which makes them safe against
+    // concurrent uploads of the same name.
+    async fn persist(
+        mut self,
+        dir: &Path,
+        name: &str,
+        overwrite: &Overwrite,
+    ) -> Result<(String, bool), StatusCode> {
+        self.file
+            .sync_all()
+            .await
+            .map_err(|err| io_status(err.kind()))?;
+        if dir.join(name).is_dir() {
+            return Err(StatusCode::CONFLICT);
+        }
+
+        if let Overwrite::Always = overwrite {
+            let replaced = fs::metadata(dir.join(name)).await.is_ok();
+            fs::rename(&self.path, dir.join(name))
+                .await
+                .map_err(|err| io_status(err.kind()))?;
+            self.created_dirs.clear();
+
+            return Ok((String::from(name), replaced));
+        }
+        for i in 0.. {
+            let candidate = match i {
+                0 => String::from(name),
+                i => numbered(name, i),
+            };
+            match fs::hard_link(&self.path, dir.join(&candidate)).await {
+                Ok(()) => {
+                    self.created_dirs.clear();
+                    return Ok((candidate, false));
+                }
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                    if let Overwrite::Never = overwrite {
+                        return Err(StatusCode::CONFLICT);
+                    }
+                }
+                Err(err) => return Err(io_status(err.kind())),
+            }
+        }
+
+        Err(StatusCode::CONFLICT)
+    }
+}
+
+impl Drop for TempUpload {
+    fn drop(&mut self) {
+        // Already gone after a rename
+        let _ = std::fs::remove_file(&self.path);
+        remove_dirs(&self.created_dirs);
+    }
+}
+
+// Stores the request body at the requested path, creating missing directories
+pub async fn put(headers: HeaderMap, uri: Uri, mut body: Body, args: &cli::Args) -> Response {
+    if !authorized(&headers, args) {
+        return error(StatusCode::UNAUTHORIZED, &headers, &uri, args).await;
+    }
+    let (mount, path) = match target(&headers, &uri, args) {
+        Some(target) => target,
+        None => return error(StatusCode::NOT_FOUND, &headers, &uri, args).await,
+    };
+    let (dir, name) = match path.rsplit_once('/') {
+        Some((dir, name)) => (mount.path.join(dir), name),
+        None => (mount.path.clone(), path.as_str()),
+    };
+    if name.is_empty() {
+        return error(StatusCode::METHOD_NOT_ALLOWED, &headers, &uri, args).await;
+    }
+    // Protected files can't be created or replaced either
+    if let Some(status) = access::check(mount, &path, false, args).status() {
+        return error(status, &headers, &uri, args).await;
+    }
+    let content_length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if content_length.is_some_and(|length| length > args.max_upload_size) {
+        return error(StatusCode::PAYLOAD_TOO_LARGE, &headers, &uri, args).await;
+    }
+
+    let mut upload = match TempUpload::create(&dir, args.max_upload_size).await {
+        Ok(upload) => upload,
+        Err(status) => return error(status, &headers, &uri, args).await,
+    };
+    while let Some(chunk) = body.data().await {
+        let result = match chunk {
+            Ok(chunk) => upload.write(&chunk).await,
+            Err(_) => Err(StatusCode::BAD_REQUEST),
+        };
+        if let Err(status) = result {
+            return error(status, &headers, &uri, args).await;
+        }
+    }
+
+    let (stored, replaced) = match upload.persist(&dir, name, &args.upload_overwrite).await {
+        Ok(stored) => stored,
+        Err(status) => return error(status, &headers, &uri, args).await,
+    };
+    let path = match path.rsplit_once('/') {
+        Some((dir, _)) => format!("{}/{}", dir, stored),
+        None => stored,
+    };
+    let status = if replaced {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::CREATED
+    };
+
+    (
+        status,
+        SetHeader(header::LOCATION.to_string(), location(mount, &path, args)),
+    )
+        .into_response()
+}
+
+// Stores the files of a `multipart/form-data` form in the requested directory. Responds with the
+// paths of the stored files, one per line.
+pub async fn post(
+    headers: HeaderMap,
+    uri: Uri,
+    multipart: Result<Multipart, MultipartRejection>,
+    args: &cli::Args,
+) -> Response {
+    if !authorized(&headers, args) {
+        return error(StatusCode::UNAUTHORIZED, &headers, &uri, args).await;
+    }
+    let (mount, path) = match target(&headers, &uri, args) {
+        Some(target) => target,
+        None => return error(StatusCode::NOT_FOUND, &headers, &uri, args).await,
+    };
+    let path = path.trim_end_matches('/');
+    let dir = mount.path.join(path);
+    if !dir.is_dir() {
+        return error(StatusCode::NOT_FOUND, &headers, &uri, args).await;
+    }
+    if let Some(status) = access::check(mount, path, true, args).status() {
+        return error(status, &headers, &uri, args).await;
+    }
+    let mut multipart = match multipart {
+        Ok(multipart) => multipart,
+        Err(_) => return error(StatusCode::UNSUPPORTED_MEDIA_TYPE, &headers, &uri, args).await,
+    };
+
+    let root = mount.prefix.is_empty() && path.is_empty();
+    let mut remaining = args.max_upload_size;
+    let mut stored = vec![];
+    loop {
+        let mut field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(_) => return error(StatusCode::BAD_REQUEST, &headers, &uri, args).await,
+        };
+        // Fields without a file name, like text inputs, are ignored
+        let name = match field.file_name().and_then(file_name) {
+            Some(name) if !(root && name == redirects::REDIRECTS_FILE) => name,
+            _ => continue,
+        };
+        let relative = match path {
+            "" => name.clone(),
+            path => format!("{}/{}", path, name),
+        };
+        if let Some(status) = access::check(mount, &relative, false, args).status() {
+            return error(status, &headers, &uri, args).await;
+        }
+
+        let mut upload = match TempUpload::create(&dir, remaining).await {
+            Ok(upload) => upload,
+            Err(status) => return error(status, &headers, &uri, args).await,
+        };
+        loop {
+            let result = match field.chunk().await {
+                Ok(Some(chunk)) => upload.write(&chunk).await,
+                Ok(None) => break,
+                Err(_) => Err(StatusCode::BAD_REQUEST),
+            };
+            if let Err(status) = result {
+                return error(status, &headers, &uri, args).await;
+            }
+        }
+        remaining -= upload.size;
+
+        let name = match upload.persist(&dir, &name, &args.upload_overwrite).await {
+            Ok((name, _)) => name,
+            Err(status) => return error(status, &headers, &uri, args).await,
+        };
+        let path = match path {
+            "" => name,
+            path => format!("{}/{}", path, name),
+        };
+        stored.push(location(mount, &path, args));
+    }
+    if stored.is_empty() {
+        return error(StatusCode::BAD_REQUEST, &headers, &uri, args).await;
+    }
+
+    (
+        StatusCode::CREATED,
+        SetHeader(
+            header::CONTENT_TYPE.to_string(),
+            String::from("text/plain; charset=utf-8"),
+        ),
+        stored.join("\n") + "\n",
+    )
+        .into_response()
+}
+
+// Deletes a single file, directories are kept
+pub async fn delete(headers: HeaderMap, uri: Uri, args: &cli::Args) -> Response {
+    if !authorized(&headers, args) {
+        return error(StatusCode::UNAUTHORIZED, &headers, &uri, args).await;
+    }
+    let (mount, path) = match target(&headers, &uri, args) {
+        Some((mount, path)) if !path.is_empty() && !path.ends_with('/') => (mount, path),
+        Some(_) => return error(StatusCode::METHOD_NOT_ALLOWED, &headers, &uri, args).await,
+        None => return error(StatusCode::NOT_FOUND, &headers, &uri, args).await,
+    };
+    if let Some(status) = access::check(mount, &path, false, args).status() {
+        return error(status, &headers, &uri, args).await;
+    }
+    let file = mount.path.join(path);
+
+    let result = match fs::symlink_metadata(&file).await {
+        Ok(metadata) if metadata.is_dir() => Err(StatusCode::CONFLICT),
+        Ok(_) => fs::remove_file(&file)
+            .await
+            .map_err(|err| io_status(err.kind())),
+        Err(_) if file_in_the_way(&file).await => Err(StatusCode::CONFLICT),
+        Err(err) => Err(io_status(err.kind())),
+    };
+    match result {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(status) => error(status, &headers, &uri, args).await,
+    }
+}
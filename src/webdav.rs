@@ -0,0 +1,220 @@
+use crate::{
+    access::{self, Access},
+    cli, hosts, mime_types,
+    mounts::{self, Mount},
+    redirects,
+    server::{self, SetHeader},
+};
+use axum::{
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+use std::{fmt::Write, fs::Metadata, path::Path, time::UNIX_EPOCH};
+use tokio::fs;
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+// The methods the server answers, listed by `OPTIONS`
+fn allowed_methods(args: &cli::Args) -> String {
+    let mut methods = vec!["OPTIONS", "GET", "HEAD", "PROPFIND"];
+    if args.allow_upload {
+        methods.extend(["PUT", "POST"]);
+    }
+    if args.allow_delete {
+        methods.push("DELETE");
+    }
+
+    methods.join(", ")
+}
+
+pub async fn options(args: &cli::Args) -> Response {
+    (
+        SetHeader(String::from("DAV"), String::from("1")),
+        SetHeader(header::ALLOW.to_string(), allowed_methods(args)),
+        // Makes Windows' WebDAV client talk WebDAV instead of FrontPage extensions
+        SetHeader(String::from("MS-Author-Via"), String::from("DAV")),
+    )
+        .into_response()
+}
+
+// Weak since it's derived from the size and modification time instead of the contents
+fn etag(metadata: &Metadata) -> Option<String> {
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+
+    Some(format!(
+        "W/\"{:x}-{:x}\"",
+        metadata.len(),
+        modified.as_nanos()
+    ))
+}
+
+// Describes a file or directory. Directory hrefs end with a slash.
+fn entry(
+    out: &mut String,
+    href: &str,
+    name: &str,
+    path: &Path,
+    metadata: &Metadata,
+    args: &cli::Args,
+) {
+    let _ = write!(
+        out,
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop><D:displayname>{}</D:displayname>",
+        escape(href),
+        escape(name)
+    );
+    if metadata.is_dir() {
+        out.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+    } else {
+        let _ = write!(
+            out,
+            "<D:resourcetype/><D:getcontentlength>{}</D:getcontentlength><D:getcontenttype>{}</D:getcontenttype>",
+            metadata.len(),
+            escape(&mime_types::of_file(path, args))
+        );
+    }
+    if let Ok(modified) = metadata.modified() {
+        let _ = write!(
+            out,
+            "<D:getlastmodified>{}</D:getlastmodified>",
+            httpdate::fmt_http_date(modified)
+        );
+    }
+    if let Some(etag) = etag(metadata) {
+        let _ = write!(out, "<D:getetag>{}</D:getetag>", escape(&etag));
+    }
+    out.push_str("</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>");
+}
+
+// Mounts placed directly inside of the directory at `url`, like `/docs` inside of `/`
+fn child_mounts<'a>(
+    mounts: &'a [Mount],
+    url: &'a str,
+) -> impl Iterator<Item = (&'a str, &'a Mount)> {
+    mounts.iter().filter_map(move |mount| {
+        let (parent, name) = mount.prefix.rsplit_once('/')?;
+
+        (parent == url && !name.is_empty()).then_some((name, mount))
+    })
+}
+
+// Lists the properties of a file, or of a directory and its children unless `Depth: 0` is given.
+// Infinite depth isn't supported and treated like `Depth: 1`.
+pub async fn propfind(headers: HeaderMap, uri: Uri, args: &cli::Args) -> Response {
+    let host = hosts::find(&args.hosts, hosts::host_name(&headers, &uri).as_deref());
+    let path = match server::strip_base_path(uri.path(), args).and_then(server::relative_path) {
+        Some(path) => path,
+        None => {
+            return server::error_response(StatusCode::NOT_FOUND, &headers, &uri, args)
+                .await
+                .into_response()
+        }
+    };
+    let (mount, path) = mounts::find(&host.mounts, &path);
+    let path = path.trim_end_matches('/');
+    let file = mount.path.join(path);
+    let metadata = match fs::metadata(&file).await {
+        Ok(metadata) if !(mount.prefix.is_empty() && path == redirects::REDIRECTS_FILE) => metadata,
+        _ => {
+            return server::error_response(StatusCode::NOT_FOUND, &headers, &uri, args)
+                .await
+                .into_response()
+        }
+    };
+    if let Some(status) = access::check(mount, path, metadata.is_dir(), args).status() {
+        return server::error_response(status, &headers, &uri, args)
+            .await
+            .into_response();
+    }
+
+    // The URL of the resource without the base path or a trailing slash
+    let url = match path {
+        "" => mount.prefix.clone(),
+        path => format!("{}/{}", mount.prefix, path),
+    };
+    let base_path = args.base_path.as_deref().unwrap_or("");
+    let href = |url: &str, dir: bool| {
+        let slash = if dir { "/" } else { "" };
+        format!("{}{}{}", base_path, server::encode_path(url), slash)
+    };
+    let name = url.rsplit('/').next().unwrap_or_default();
+
+    let mut out =
+        String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?><D:multistatus xmlns:D=\"DAV:\">");
+    entry(
+        &mut out,
+        &href(&url, metadata.is_dir()),
+        name,
+        &file,
+        &metadata,
+        args,
+    );
+
+    let depth = headers.get("Depth").and_then(|depth| depth.to_str().ok());
+    if metadata.is_dir() && depth != Some("0") {
+        let mut children = vec![];
+        for (name, mount) in child_mounts(&host.mounts, &url) {
+            if let Ok(metadata) = fs::metadata(&mount.path).await {
+                children.push((String::from(name), mount.path.clone(), metadata));
+            }
+        }
+
+        if let Ok(mut dir) = fs::read_dir(&file).await {
+            while let Ok(Some(child)) = dir.next_entry().await {
+                let name = match child.file_name().into_string() {
+                    Ok(name) => name,
+                    Err(_) => continue,
+                };
+                // Mounts hide the directories they're placed on
+                if children.iter().any(|(other, _, _)| *other == name)
+                    || (url.is_empty() && name == redirects::REDIRECTS_FILE)
+                {
+                    continue;
+                }
+                let metadata = match fs::metadata(child.path()).await {
+                    Ok(metadata) => metadata,
+                    Err(_) => continue,
+                };
+                // Hidden and forbidden files aren't listed either
+                let relative = match path {
+                    "" => name.clone(),
+                    path => format!("{}/{}", path, name),
+                };
+                if access::check(mount, &relative, metadata.is_dir(), args) == Access::Allowed {
+                    children.push((name, child.path(), metadata));
+                }
+            }
+        }
+
+        children.sort_by(|a, b| a.0.cmp(&b.0));
+        for (name, path, metadata) in children {
+            let url = format!("{}/{}", url, name);
+            entry(
+                &mut out,
+                &href(&url, metadata.is_dir()),
+                &name,
+                &path,
+                &metadata,
+                args,
+            );
+        }
+    }
+    out.push_str("</D:multistatus>");
+
+    (
+        StatusCode::MULTI_STATUS,
+        SetHeader(
+            header::CONTENT_TYPE.to_string(),
+            String::from("application/xml; charset=utf-8"),
+        ),
+        out,
+    )
+        .into_response()
+}
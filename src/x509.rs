@@ -0,0 +1,188 @@
+// Just enough of X.509 to read certificates, without pulling in a parser
+
+// Reads a DER element, returning its tag, its contents and what follows it
+fn der(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&len, rest) = rest.split_first()?;
+    let (len, rest) = match len {
+        len if len < 0x80 => (len as usize, rest),
+        len => {
+            let bytes = (len & 0x7f) as usize;
+            if bytes == 0 || bytes > 4 || rest.len() < bytes {
+                return None;
+            }
+            let len = rest[..bytes]
+                .iter()
+                .fold(0, |len, byte| len << 8 | *byte as usize);
+            (len, &rest[bytes..])
+        }
+    };
+    if rest.len() < len {
+        return None;
+    }
+
+    Some((tag, &rest[..len], &rest[len..]))
+}
+
+// Converts an UTCTime or GeneralizedTime to seconds since the Unix epoch
+fn parse_time(tag: u8, time: &[u8]) -> Option<u64> {
+    let time = std::str::from_utf8(time).ok()?;
+    let (year, rest) = match tag {
+        0x17 => {
+            let year: i64 = time.get(..2)?.parse().ok()?;
+            (
+                if year < 50 { 2000 + year } else { 1900 + year },
+                &time[2..],
+            )
+        }
+        0x18 => (time.get(..4)?.parse().ok()?, &time[4..]),
+        _ => return None,
+    };
+    let field = |i: usize| -> Option<i64> { rest.get(i..i + 2)?.parse().ok() };
+    let (month, day) = (field(0)?, field(2)?);
+    let (hour, minute, second) = (field(4)?, field(6)?, field(8)?);
+
+    // Howard Hinnant's `days_from_civil`
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    u64::try_from(days * 86400 + hour * 3600 + minute * 60 + second).ok()
+}
+
+// The fields of the `TBSCertificate` of a DER certificate, starting at the serial number
+fn tbs_fields(cert: &[u8]) -> Option<&[u8]> {
+    let (_, cert, _) = der(cert)?;
+    let (_, tbs_certificate, _) = der(cert)?;
+    // The version is optional and tagged with [0]
+    match tbs_certificate.first() {
+        Some(0xa0) => Some(der(tbs_certificate)?.2),
+        _ => Some(tbs_certificate),
+    }
+}
+
+// The `notBefore` and `notAfter` of a DER certificate
+pub fn validity(cert: &[u8]) -> Option<(u64, u64)> {
+    let mut rest = tbs_fields(cert)?;
+    // The serial number, the signature algorithm and the issuer
+    for _ in 0..3 {
+        rest = der(rest)?.2;
+    }
+    let (_, validity, _) = der(rest)?;
+    let (tag, not_before, rest) = der(validity)?;
+    let not_before = parse_time(tag, not_before)?;
+    let (tag, not_after, _) = der(rest)?;
+
+    Some((not_before, parse_time(tag, not_after)?))
+}
+
+// Short names of the usual attributes, the others are written as dotted OIDs
+const ATTRIBUTES: &[(&str, &str)] = &[
+    ("2.5.4.3", "CN"),
+    ("2.5.4.5", "serialNumber"),
+    ("2.5.4.6", "C"),
+    ("2.5.4.7", "L"),
+    ("2.5.4.8", "ST"),
+    ("2.5.4.9", "STREET"),
+    ("2.5.4.10", "O"),
+    ("2.5.4.11", "OU"),
+    ("0.9.2342.19200300.100.1.1", "UID"),
+    ("0.9.2342.19200300.100.1.25", "DC"),
+    ("1.2.840.113549.1.9.1", "emailAddress"),
+];
+
+fn parse_oid(oid: &[u8]) -> Option<String> {
+    let (&first, rest) = oid.split_first()?;
+    let mut arcs = vec![u64::from(first / 40), u64::from(first % 40)];
+    let mut arc: u64 = 0;
+    for byte in rest {
+        arc = arc.checked_mul(128)? | u64::from(byte & 0x7f);
+        if byte & 0x80 == 0 {
+            arcs.push(arc);
+            arc = 0;
+        }
+    }
+
+    Some(
+        arcs.iter()
+            .map(|arc| arc.to_string())
+            .collect::<Vec<_>>()
+            .join("."),
+    )
+}
+
+fn parse_string(tag: u8, value: &[u8]) -> Option<String> {
+    match tag {
+        // UTF8String, PrintableString and IA5String
+        0x0c | 0x13 | 0x16 => String::from_utf8(value.to_vec()).ok(),
+        // TeletexString, mostly used for Latin-1
+        0x14 => Some(value.iter().map(|&byte| char::from(byte)).collect()),
+        // BMPString
+        0x1e => String::from_utf16(
+            &value
+                .chunks_exact(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                .collect::<Vec<_>>(),
+        )
+        .ok(),
+        _ => None,
+    }
+}
+
+// Escapes the special characters of RFC 4514
+fn escape(value: &str) -> String {
+    let last = value.chars().count().saturating_sub(1);
+    let mut escaped = String::with_capacity(value.len());
+    for (i, c) in value.chars().enumerate() {
+        let special = matches!(c, ',' | '+' | '"' | '\\' | '<' | '>' | ';')
+            || (i == 0 && matches!(c, ' ' | '#'))
+            || (i == last && c == ' ');
+        if special {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+// The subject of a DER certificate as an RFC 4514 string like `CN=mirror-1,OU=Mirrors,O=Example`,
+// the format of nginx' `$ssl_client_s_dn`
+pub fn subject(cert: &[u8]) -> Option<String> {
+    let mut rest = tbs_fields(cert)?;
+    // The serial number, the signature algorithm, the issuer and the validity
+    for _ in 0..4 {
+        rest = der(rest)?.2;
+    }
+    let (_, mut name, _) = der(rest)?;
+
+    let mut rdns = vec![];
+    while !name.is_empty() {
+        let (_, mut set, rest) = der(name)?;
+        name = rest;
+
+        let mut attributes = vec![];
+        while !set.is_empty() {
+            let (_, attribute, rest) = der(set)?;
+            set = rest;
+
+            let (_, oid, value) = der(attribute)?;
+            let oid = parse_oid(oid)?;
+            let (tag, value, _) = der(value)?;
+            let value = parse_string(tag, value)?;
+            let name = ATTRIBUTES
+                .iter()
+                .find(|(known, _)| *known == oid)
+                .map_or(oid.as_str(), |(_, name)| name);
+            attributes.push(format!("{}={}", name, escape(&value)));
+        }
+        rdns.push(attributes.join("+"));
+    }
+    // The most significant attribute comes last
+    rdns.reverse();
+
+    Some(rdns.join(","))
+}